@@ -0,0 +1,455 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::prelude::{AsRawFd, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, error, info, warn};
+
+use crate::{
+    execute::{
+        metadata::{self, Attribute, Unpreserved},
+        verify::Hasher,
+        HashAlgorithm, Journal, Stage,
+    },
+    Move,
+};
+
+/// A planned [`Move`] which could not be carried out.
+#[derive(Debug)]
+pub struct MoveError {
+    pub failed: Move,
+    pub error: io::Error,
+}
+
+/// Outcome of executing a plan.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Moves which were not completed; their sources remain in place.
+    pub failures: Vec<MoveError>,
+    /// Metadata which could not be carried over to a copied file or created directory.
+    pub unpreserved: Vec<Unpreserved>,
+}
+
+/// State of a single execution run.
+struct Progress {
+    journal: Journal,
+    report: Report,
+    /// Directories created under a target root, with the metadata of their source counterpart.
+    created: Vec<(fs::Metadata, PathBuf)>,
+    /// Targets which later moves make further links of.
+    linked: HashSet<PathBuf>,
+}
+
+/// Performs the [`Move`]s produced by [`crate::State::relocate`].
+///
+/// Moves within a single filesystem are a `rename(2)`; moves between
+/// filesystems copy the file and then unlink the source. Copies, and any
+/// directories created for them, are given the ownership, mode, extended
+/// attributes and timestamps of their source. A directory move creates its
+/// target likewise, if missing, then removes the (by then empty) source.
+#[derive(Debug, Default, Clone)]
+pub struct Executor {
+    journal: Option<PathBuf>,
+    verify: Option<HashAlgorithm>,
+}
+
+impl Executor {
+    /// Record progress in a new journal at `path`, from which an interrupted run can be resumed.
+    pub fn journal(mut self, path: impl Into<PathBuf>) -> Self {
+        self.journal = Some(path.into());
+        self
+    }
+
+    /// Compare a checksum of each cross-filesystem copy with its source before removing the source.
+    pub fn verify(mut self, algorithm: HashAlgorithm) -> Self {
+        self.verify = Some(algorithm);
+        self
+    }
+
+    /// Perform each move in turn, reporting those which failed.
+    ///
+    /// A failed move leaves its source in place; later moves are still attempted.
+    pub fn execute(&self, moves: &[Move]) -> io::Result<Report> {
+        let journal = match &self.journal {
+            Some(path) => Journal::create(path, moves)?,
+            None => Journal::disabled(),
+        };
+        let moves = moves.iter().map(|m| (m.clone(), Stage::Pending));
+        Ok(self.run(moves, journal, false))
+    }
+
+    /// Finish the moves recorded in the journal at `path`, skipping those already complete.
+    pub fn resume(&self, path: &Path) -> io::Result<Report> {
+        let (journal, moves) = Journal::open(path)?;
+        info!(
+            "Resuming {} of {} moves from {:?}",
+            moves
+                .iter()
+                .filter(|(_, stage)| *stage != Stage::SourceRemoved)
+                .count(),
+            moves.len(),
+            path
+        );
+        Ok(self.run(moves.into_iter(), journal, true))
+    }
+
+    fn run(
+        &self,
+        moves: impl Iterator<Item = (Move, Stage)>,
+        journal: Journal,
+        resuming: bool,
+    ) -> Report {
+        let moves = moves.collect::<Vec<_>>();
+        let mut progress = Progress {
+            journal,
+            report: Report::default(),
+            created: Vec::new(),
+            linked: moves
+                .iter()
+                .filter_map(|(m, _)| m.hard_link.clone())
+                .collect(),
+        };
+        for (index, (m, stage)) in moves.into_iter().enumerate() {
+            if stage == Stage::SourceRemoved {
+                debug!("already moved {:?} to {:?}", m.source, m.target);
+                continue;
+            }
+            info!("Move {:?} to {:?}", m.source, m.target);
+            let result = if resuming {
+                Self::reconcile(&m, stage)
+            } else {
+                Ok(stage)
+            }
+            .and_then(|stage| self.advance(index, &m, stage, &mut progress));
+            if let Err(e) = result {
+                error!("Failed to move {:?} to {:?}: {}", m.source, m.target, e);
+                progress.report.failures.push(MoveError {
+                    failed: m,
+                    error: e,
+                });
+            }
+        }
+        // Filling the created directories has updated their timestamps
+        for (source_metadata, target) in progress.created.iter().rev() {
+            if let Err(error) = metadata::preserve_times(source_metadata, target) {
+                warn!("Unable to preserve timestamps on {:?}: {}", target, error);
+                progress.report.unpreserved.push(Unpreserved {
+                    path: target.clone(),
+                    attribute: Attribute::Timestamps,
+                    error,
+                });
+            }
+        }
+        progress.report
+    }
+
+    /// Correct the journalled stage of an interrupted move against what is on disk.
+    fn reconcile(m: &Move, stage: Stage) -> io::Result<Stage> {
+        let source_exists = fs::symlink_metadata(&m.source).is_ok();
+        let target_exists = fs::symlink_metadata(&m.target).is_ok();
+        match (stage, source_exists, target_exists) {
+            // A rename completed, but was not journalled
+            (Stage::Pending, false, true) => Ok(Stage::SourceRemoved),
+            // A link was made, but not journalled
+            (Stage::Pending, true, true) if Self::linked(m).unwrap_or(false) => Ok(Stage::Verified),
+            (Stage::Verified, _, true) => Ok(stage),
+            (_, false, _) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("source {:?} missing, journalled as {:?}", m.source, stage),
+            )),
+            (Stage::Copying, true, true) => {
+                warn!("discarding partial copy {:?}", m.target);
+                fs::remove_file(&m.target)?;
+                Ok(stage)
+            }
+            (Stage::Pending | Stage::Copying, true, _) | (_, true, true) => Ok(stage),
+            // The copy has gone missing, so start again
+            (_, true, false) => Ok(Stage::Pending),
+        }
+    }
+
+    /// Carry a move on from `stage` to completion, journalling each stage reached.
+    fn advance(
+        &self,
+        index: usize,
+        m: &Move,
+        mut stage: Stage,
+        progress: &mut Progress,
+    ) -> io::Result<()> {
+        let mut source_digest = None;
+        loop {
+            stage = match stage {
+                Stage::Pending => self.start(index, m, progress)?,
+                Stage::Copying => {
+                    source_digest = self.copy(&m.source, &m.target, progress)?;
+                    Stage::Copied
+                }
+                Stage::Copied => {
+                    self.check_copy(&m.source, &m.target, source_digest.take())?;
+                    Stage::Verified
+                }
+                Stage::Verified => {
+                    let removed = if m.directory {
+                        fs::remove_dir(&m.source)
+                    } else {
+                        fs::remove_file(&m.source)
+                    };
+                    match removed {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                    Stage::SourceRemoved
+                }
+                Stage::SourceRemoved => return Ok(()),
+            };
+            progress.journal.record(index, stage)?;
+        }
+    }
+
+    /// Rename within a filesystem, make a link or directory, or prepare to copy between filesystems.
+    fn start(&self, index: usize, m: &Move, progress: &mut Progress) -> io::Result<Stage> {
+        let source_metadata = fs::symlink_metadata(&m.source)?;
+        if m.directory {
+            return Self::make_directory(m, progress);
+        }
+        if fs::symlink_metadata(&m.target).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("target {:?} already exists", m.target),
+            ));
+        }
+        Self::create_parents(m, progress)?;
+        if m.hard_link.is_none() && progress.linked.contains(&m.target) {
+            // The further links must still be of this file when they follow it
+            progress.journal.record_inode(
+                index,
+                &m.target,
+                source_metadata.dev(),
+                source_metadata.ino(),
+            )?;
+        }
+        if source_metadata.dev() == Self::target_dev(&m.target)? && m.symlink.is_none() {
+            debug!("rename {:?} to {:?}", m.source, m.target);
+            fs::rename(&m.source, &m.target)?;
+            Ok(Stage::SourceRemoved)
+        } else if source_metadata.file_type().is_symlink() && m.hard_link.is_none() {
+            let contents = match &m.symlink {
+                Some(contents) => contents.clone(),
+                None => fs::read_link(&m.source)?,
+            };
+            debug!("link {:?} to {:?}", m.target, contents);
+            std::os::unix::fs::symlink(&contents, &m.target)?;
+            progress
+                .report
+                .unpreserved
+                .extend(metadata::preserve(&m.source, &m.target)?);
+            Ok(Stage::Verified)
+        } else if let Some(hard_link) = &m.hard_link {
+            // The data was moved, and checked, along with the file's first link
+            let moved = progress.journal.inode(hard_link).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no record of the file moved to {:?}", hard_link),
+                )
+            })?;
+            if moved != (source_metadata.dev(), source_metadata.ino())
+                || fs::symlink_metadata(hard_link)?.len() != source_metadata.len()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} does not match {:?}", hard_link, m.source),
+                ));
+            }
+            debug!("link {:?} to {:?}", m.target, hard_link);
+            fs::hard_link(hard_link, &m.target)?;
+            Ok(Stage::Verified)
+        } else {
+            Ok(Stage::Copying)
+        }
+    }
+
+    /// Create the target directory like the source, unless it already exists.
+    fn make_directory(m: &Move, progress: &mut Progress) -> io::Result<Stage> {
+        match fs::symlink_metadata(&m.target) {
+            Ok(metadata) if metadata.is_dir() => {
+                debug!("{:?} already exists", m.target);
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("target {:?} already exists", m.target),
+                ))
+            }
+            Err(_) => {
+                Self::create_parents(m, progress)?;
+                debug!("create {:?} like {:?}", m.target, m.source);
+                let source_metadata = fs::symlink_metadata(&m.source)?;
+                fs::create_dir(&m.target)?;
+                progress
+                    .report
+                    .unpreserved
+                    .extend(metadata::preserve(&m.source, &m.target)?);
+                progress.created.push((source_metadata, m.target.clone()));
+            }
+        }
+        Ok(Stage::Verified)
+    }
+
+    /// Whether the target is already the link, symbolic or hard, which the move would make.
+    fn linked(m: &Move) -> io::Result<bool> {
+        let target = fs::symlink_metadata(&m.target)?;
+        if let Some(hard_link) = &m.hard_link {
+            let hard_link = fs::symlink_metadata(hard_link)?;
+            return Ok(target.dev() == hard_link.dev() && target.ino() == hard_link.ino());
+        }
+        if !fs::symlink_metadata(&m.source)?.file_type().is_symlink() {
+            return Ok(false);
+        }
+        let contents = match &m.symlink {
+            Some(contents) => contents.clone(),
+            None => fs::read_link(&m.source)?,
+        };
+        Ok(target.file_type().is_symlink() && fs::read_link(&m.target)? == contents)
+    }
+
+    /// Create the missing ancestors of the target, matching their counterparts above the source.
+    fn create_parents(m: &Move, progress: &mut Progress) -> io::Result<()> {
+        let mut missing = Vec::new();
+        let mut source_dir = m.source.parent();
+        let mut target_dir = m.target.parent();
+        while let (Some(source), Some(target)) = (source_dir, target_dir) {
+            if fs::symlink_metadata(target).is_ok() {
+                break;
+            }
+            missing.push((source, target));
+            source_dir = source.parent();
+            target_dir = target.parent();
+        }
+        for (source, target) in missing.into_iter().rev() {
+            debug!("create {:?} like {:?}", target, source);
+            let source_metadata = fs::symlink_metadata(source)?;
+            fs::create_dir(target)?;
+            progress
+                .report
+                .unpreserved
+                .extend(metadata::preserve(source, target)?);
+            progress
+                .created
+                .push((source_metadata, target.to_path_buf()));
+        }
+        Ok(())
+    }
+
+    /// Device id of the nearest existing ancestor of `target`.
+    fn target_dev(target: &Path) -> io::Result<u64> {
+        let mut path = target.parent().map(Path::to_path_buf).unwrap_or_default();
+        loop {
+            match fs::metadata(&path) {
+                Ok(metadata) => return Ok(metadata.dev()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            path = match path.parent() {
+                Some(parent) => parent.to_path_buf(),
+                None => PathBuf::from("."),
+            };
+        }
+    }
+
+    /// Copy `source` to a new `target`, returning the source checksum when verifying.
+    fn copy(
+        &self,
+        source: &Path,
+        target: &Path,
+        progress: &mut Progress,
+    ) -> io::Result<Option<Vec<u8>>> {
+        debug!("copy {:?} to {:?}", source, target);
+        let reader = File::open(source)?;
+        let mut writer = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(target)?;
+        let result = self.copy_contents(reader, &mut writer).and_then(|digest| {
+            progress
+                .report
+                .unpreserved
+                .extend(metadata::preserve(source, target)?);
+            writer.sync_all()?;
+            if digest.is_some() {
+                // Drop the cached pages, so verification re-reads what reached the device
+                // SAFETY: `writer` is open for the whole call, and the advice reads no memory.
+                let advised = unsafe {
+                    libc::posix_fadvise(writer.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED)
+                };
+                if advised != 0 {
+                    // Only a hint: verification then reads the cached pages
+                    debug!(
+                        "unable to drop cached pages of {:?}: {}",
+                        target,
+                        io::Error::from_raw_os_error(advised)
+                    );
+                }
+            }
+            Ok(digest)
+        });
+        if result.is_err() {
+            // Do not leave a partial copy behind
+            let _ = fs::remove_file(target);
+        }
+        result
+    }
+
+    fn copy_contents(&self, mut reader: File, writer: &mut File) -> io::Result<Option<Vec<u8>>> {
+        let mut hasher = self.verify.map(Hasher::from);
+        let mut buffer = vec![0; 1 << 20];
+        loop {
+            let len = reader.read(&mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&buffer[..len]);
+            }
+            writer.write_all(&buffer[..len])?;
+        }
+        Ok(hasher.map(Hasher::finish))
+    }
+
+    /// Check `target` matches `source`, by length and (when verifying) checksum.
+    ///
+    /// `source_digest` is the checksum taken while copying, if this run made the copy.
+    fn check_copy(
+        &self,
+        source: &Path,
+        target: &Path,
+        source_digest: Option<Vec<u8>>,
+    ) -> io::Result<()> {
+        let source_len = fs::metadata(source)?.len();
+        let target_len = fs::metadata(target)?.len();
+        if source_len != target_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "copy {target:?} is {target_len} bytes, source {source:?} is {source_len} bytes"
+                ),
+            ));
+        }
+        if let Some(algorithm) = self.verify {
+            let source_digest = match source_digest {
+                Some(digest) => digest,
+                None => algorithm.hash_file(source)?,
+            };
+            if algorithm.hash_file(target)? != source_digest {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{algorithm:?} checksum of copy {target:?} does not match source {source:?}"),
+                ));
+            }
+            debug!("verified {:?} against {:?}", target, source);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+
+use crate::Move;
+
+const HEADER: &str = "relocation-journal 1";
+/// Final field of a pending record whose move is of a directory.
+const DIRECTORY: &str = "directory";
+/// First field of a record of the device and inode a move's source had, before it moved.
+const INODE: &str = "inode";
+
+/// Progress of a single [`Move`], in the order the stages are reached.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Stage {
+    /// Planned, nothing done yet.
+    Pending,
+    /// Copy to the target started; the target may be incomplete.
+    Copying,
+    /// Target fully written and synced to disk.
+    Copied,
+    /// Target checked against the source.
+    Verified,
+    /// Source gone; the move is complete.
+    SourceRemoved,
+}
+
+impl Stage {
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Pending => "pending",
+            Stage::Copying => "copying",
+            Stage::Copied => "copied",
+            Stage::Verified => "verified",
+            Stage::SourceRemoved => "source-removed",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "pending" => Some(Stage::Pending),
+            "copying" => Some(Stage::Copying),
+            "copied" => Some(Stage::Copied),
+            "verified" => Some(Stage::Verified),
+            "source-removed" => Some(Stage::SourceRemoved),
+            _ => None,
+        }
+    }
+}
+
+/// Append-only, write-ahead record of move progress.
+///
+/// Every record is synced to disk as soon as its stage is reached (and `copying`
+/// before the copy starts), so after a crash the journal never claims more
+/// progress than was made.
+///
+/// It also keeps the device and inode each file had before its first link moved,
+/// so the further links can be checked to still be of that file.
+#[derive(Debug, Default)]
+pub struct Journal {
+    file: Option<File>,
+    /// Device and inode of the source of each move recorded, by target.
+    inodes: HashMap<PathBuf, (u64, u64)>,
+}
+
+impl Journal {
+    /// A journal which records nothing.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Start a new journal at `path`, recording every move as pending.
+    ///
+    /// Refuses to overwrite an existing journal.
+    pub fn create(path: &Path, moves: &[Move]) -> io::Result<Self> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut contents = format!("{HEADER}\n").into_bytes();
+        for (index, m) in moves.iter().enumerate() {
+            contents
+                .extend_from_slice(format!("{}\t{}\t", Stage::Pending.name(), index).as_bytes());
+            contents.extend_from_slice(&escape(&m.source));
+            contents.push(b'\t');
+            contents.extend_from_slice(&escape(&m.target));
+            // Optional trailing fields, left empty where a later one is present
+            let optional = [
+                m.hard_link.as_deref().map(escape),
+                m.symlink.as_deref().map(escape),
+                m.directory.then(|| DIRECTORY.as_bytes().to_vec()),
+            ];
+            let present = optional
+                .iter()
+                .rposition(Option::is_some)
+                .map_or(0, |i| i + 1);
+            for field in &optional[..present] {
+                contents.push(b'\t');
+                contents.extend_from_slice(field.as_deref().unwrap_or_default());
+            }
+            contents.push(b'\n');
+        }
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        Ok(Self {
+            file: Some(file),
+            ..Default::default()
+        })
+    }
+
+    /// Re-open the journal at `path`, returning every move with the last stage recorded for it.
+    pub fn open(path: &Path) -> io::Result<(Self, Vec<(Move, Stage)>)> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut records: Vec<(Move, Stage)> = Vec::new();
+        let mut inodes = HashMap::new();
+        let mut line = Vec::new();
+        let mut line_number = 0;
+        let mut valid_len = 0;
+        loop {
+            line.clear();
+            let len = reader.read_until(b'\n', &mut line)?;
+            if len == 0 {
+                break;
+            }
+            line_number += 1;
+            if line.pop() != Some(b'\n') {
+                // Torn write of the final record: the step it describes was never started
+                warn!("ignoring incomplete final record in journal {path:?}");
+                break;
+            }
+            if line_number == 1 {
+                if line != HEADER.as_bytes() {
+                    return Err(invalid(path, line_number, "not a relocation journal"));
+                }
+                valid_len += len as u64;
+                continue;
+            }
+            let fields = line.split(|b| *b == b'\t').collect::<Vec<_>>();
+            if fields[0] == INODE.as_bytes() {
+                let numbers = fields[1..]
+                    .iter()
+                    .map(|f| std::str::from_utf8(f).ok()?.parse::<u64>().ok())
+                    .collect::<Option<Vec<_>>>();
+                let Some(&[index, dev, ino]) = numbers.as_deref() else {
+                    return Err(invalid(path, line_number, "malformed inode record"));
+                };
+                let (m, _) = records
+                    .get(index as usize)
+                    .ok_or_else(|| invalid(path, line_number, "unknown move index"))?;
+                inodes.insert(m.target.clone(), (dev, ino));
+                valid_len += len as u64;
+                continue;
+            }
+            let stage = std::str::from_utf8(fields[0])
+                .ok()
+                .and_then(Stage::parse)
+                .ok_or_else(|| invalid(path, line_number, "unknown stage"))?;
+            let index = fields
+                .get(1)
+                .and_then(|f| std::str::from_utf8(f).ok())
+                .and_then(|f| f.parse::<usize>().ok())
+                .ok_or_else(|| invalid(path, line_number, "bad move index"))?;
+            match (stage, fields.len()) {
+                (Stage::Pending, 4..=7) if index == records.len() => {
+                    let field = |number: usize, name: &str| {
+                        unescape(fields[number])
+                            .ok_or_else(|| invalid(path, line_number, &format!("bad {name} path")))
+                    };
+                    let optional = |number: usize, name: &str| match fields.get(number) {
+                        Some(f) if !f.is_empty() => field(number, name).map(Some),
+                        _ => Ok(None),
+                    };
+                    let m = Move {
+                        source: field(2, "source")?,
+                        target: field(3, "target")?,
+                        hard_link: optional(4, "link")?,
+                        symlink: optional(5, "symlink")?,
+                        directory: match fields.get(6) {
+                            None => false,
+                            Some(f) if *f == DIRECTORY.as_bytes() => true,
+                            Some(_) => {
+                                return Err(invalid(path, line_number, "bad directory field"))
+                            }
+                        },
+                    };
+                    records.push((m, Stage::Pending));
+                }
+                (Stage::Pending, _) => {
+                    return Err(invalid(path, line_number, "malformed pending record"))
+                }
+                (_, 2) => {
+                    let record = records
+                        .get_mut(index)
+                        .ok_or_else(|| invalid(path, line_number, "unknown move index"))?;
+                    record.1 = record.1.max(stage);
+                }
+                _ => return Err(invalid(path, line_number, "malformed record")),
+            }
+            valid_len += len as u64;
+        }
+        debug!("journal {path:?}: {records:?}");
+        let file = OpenOptions::new().append(true).open(path)?;
+        file.set_len(valid_len)?;
+        Ok((
+            Self {
+                file: Some(file),
+                inodes,
+            },
+            records,
+        ))
+    }
+
+    /// Durably record that move `index` has reached `stage`.
+    pub fn record(&mut self, index: usize, stage: Stage) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            file.write_all(format!("{}\t{}\n", stage.name(), index).as_bytes())?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Durably record the device and inode of the source of move `index`, to `target`,
+    /// before it moves.
+    pub fn record_inode(
+        &mut self,
+        index: usize,
+        target: &Path,
+        dev: u64,
+        ino: u64,
+    ) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            file.write_all(format!("{INODE}\t{index}\t{dev}\t{ino}\n").as_bytes())?;
+            file.sync_data()?;
+        }
+        self.inodes.insert(target.to_path_buf(), (dev, ino));
+        Ok(())
+    }
+
+    /// The device and inode recorded for the source of the move to `target`.
+    pub fn inode(&self, target: &Path) -> Option<(u64, u64)> {
+        self.inodes.get(target).copied()
+    }
+}
+
+fn invalid(path: &Path, line_number: usize, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{path:?} line {line_number}: {reason}"),
+    )
+}
+
+/// Percent-encode bytes which would break the line format, so any path (UTF-8 or not) round trips.
+fn escape(path: &Path) -> Vec<u8> {
+    let mut escaped = Vec::new();
+    for b in path.as_os_str().as_bytes() {
+        if b.is_ascii_graphic() && *b != b'%' || *b == b' ' {
+            escaped.push(*b);
+        } else {
+            escaped.extend_from_slice(format!("%{b:02x}").as_bytes());
+        }
+    }
+    escaped
+}
+
+fn unescape(escaped: &[u8]) -> Option<PathBuf> {
+    let mut bytes = Vec::new();
+    let mut iter = escaped.iter();
+    while let Some(b) = iter.next() {
+        if *b == b'%' {
+            let hex = [*iter.next()?, *iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(*b);
+        }
+    }
+    Some(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ffi::OsStr, fs, os::unix::ffi::OsStrExt, path::PathBuf};
+
+    use super::{Journal, Stage};
+    use crate::Move;
+
+    #[test]
+    fn round_trip() {
+        let path = PathBuf::from("test_journal_round_trip");
+        let _ = fs::remove_file(&path);
+        let moves = vec![
+            Move {
+                source: PathBuf::from("/a/c/1 %.txt"),
+                target: PathBuf::from("/b/c/1 %.txt"),
+                ..Default::default()
+            },
+            Move {
+                source: PathBuf::from(OsStr::from_bytes(b"/a/c/\xff\t\n")),
+                target: PathBuf::from(OsStr::from_bytes(b"/b/c/\xff\t\n")),
+                hard_link: Some(PathBuf::from("/b/c/1 %.txt")),
+                ..Default::default()
+            },
+            Move {
+                source: PathBuf::from("/a/c/link"),
+                target: PathBuf::from("/b/c/link"),
+                symlink: Some(PathBuf::from("/b/c/1 %.txt")),
+                ..Default::default()
+            },
+            Move {
+                source: PathBuf::from("/a/c"),
+                target: PathBuf::from("/b/c"),
+                directory: true,
+                ..Default::default()
+            },
+        ];
+        let mut journal = Journal::create(&path, &moves).unwrap();
+        journal
+            .record_inode(0, &moves[0].target, 2049, 131)
+            .unwrap();
+        journal.record(1, Stage::Copying).unwrap();
+        journal.record(1, Stage::Copied).unwrap();
+        drop(journal);
+        // Simulate a torn write
+        let mut contents = fs::read(&path).unwrap();
+        contents.extend_from_slice(b"verif");
+        fs::write(&path, contents).unwrap();
+
+        let (mut journal, records) = Journal::open(&path).unwrap();
+        assert_eq!(
+            vec![
+                (moves[0].clone(), Stage::Pending),
+                (moves[1].clone(), Stage::Copied),
+                (moves[2].clone(), Stage::Pending),
+                (moves[3].clone(), Stage::Pending)
+            ],
+            records
+        );
+        assert_eq!(Some((2049, 131)), journal.inode(&moves[0].target));
+        assert_eq!(None, journal.inode(&moves[1].target));
+        journal.record(0, Stage::SourceRemoved).unwrap();
+        drop(journal);
+
+        let (journal, records) = Journal::open(&path).unwrap();
+        assert_eq!(Stage::SourceRemoved, records[0].1);
+        assert_eq!(Some((2049, 131)), journal.inode(&moves[0].target));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    ffi::{CString, OsString},
+    fmt, fs, io,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::{lchown, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+
+/// Metadata carried over from a source to its copy.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Attribute {
+    /// Permission bits, including setuid, setgid and sticky.
+    Mode,
+    /// Owning uid and gid.
+    Ownership,
+    /// Access and modification times.
+    Timestamps,
+    /// A named extended attribute.
+    Xattr(OsString),
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Attribute::Mode => write!(f, "mode"),
+            Attribute::Ownership => write!(f, "ownership"),
+            Attribute::Timestamps => write!(f, "timestamps"),
+            Attribute::Xattr(name) => write!(f, "xattr {}", name.to_string_lossy()),
+        }
+    }
+}
+
+/// An [`Attribute`] which could not be given to `path`.
+#[derive(Debug)]
+pub struct Unpreserved {
+    pub path: PathBuf,
+    pub attribute: Attribute,
+    pub error: io::Error,
+}
+
+/// Give `target` the ownership, mode, extended attributes and timestamps of `source`.
+///
+/// Every attribute is attempted; those which could not be set are returned.
+pub(crate) fn preserve(source: &Path, target: &Path) -> io::Result<Vec<Unpreserved>> {
+    let metadata = fs::symlink_metadata(source)?;
+    let source_c = cpath(source)?;
+    let target_c = cpath(target)?;
+    let mut unpreserved = Vec::new();
+    let mut check = |attribute: Attribute, result: io::Result<()>| {
+        if let Err(error) = result {
+            warn!(
+                "Unable to preserve {} on {:?}: {}",
+                attribute, target, error
+            );
+            unpreserved.push(Unpreserved {
+                path: target.to_path_buf(),
+                attribute,
+                error,
+            });
+        }
+    };
+
+    // Ownership first, as changing it clears setuid / setgid bits and capabilities
+    check(
+        Attribute::Ownership,
+        lchown(target, Some(metadata.uid()), Some(metadata.gid())),
+    );
+    if !metadata.file_type().is_symlink() {
+        check(
+            Attribute::Mode,
+            fs::set_permissions(target, fs::Permissions::from_mode(metadata.mode())),
+        );
+    }
+    match xattr_names(&source_c) {
+        Ok(names) => {
+            for name in names {
+                let result = get_xattr(&source_c, &name)
+                    .and_then(|value| set_xattr(&target_c, &name, &value));
+                check(Attribute::Xattr(OsString::from_vec(name)), result);
+            }
+        }
+        Err(e) => debug!("cannot list xattrs of {:?}: {}", source, e),
+    }
+    check(Attribute::Timestamps, set_times(&target_c, &metadata));
+    Ok(unpreserved)
+}
+
+/// Give `target` the timestamps recorded in `metadata`, e.g. once a directory's contents are complete.
+pub(crate) fn preserve_times(metadata: &fs::Metadata, target: &Path) -> io::Result<()> {
+    set_times(&cpath(target)?, metadata)
+}
+
+fn cpath(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn set_times(path: &CString, metadata: &fs::Metadata) -> io::Result<()> {
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec(),
+        },
+    ];
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn xattr_names(path: &CString) -> io::Result<Vec<Vec<u8>>> {
+    let mut buffer: Vec<u8> = Vec::new();
+    loop {
+        let len = unsafe {
+            libc::llistxattr(
+                path.as_ptr(),
+                buffer.as_mut_ptr() as *mut libc::c_char,
+                buffer.len(),
+            )
+        };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            // Grown since sized; try again
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                buffer.clear();
+                continue;
+            }
+            return Err(e);
+        }
+        if buffer.is_empty() && len > 0 {
+            buffer.resize(len as usize, 0);
+            continue;
+        }
+        buffer.truncate(len as usize);
+        return Ok(buffer
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect());
+    }
+}
+
+fn get_xattr(path: &CString, name: &[u8]) -> io::Result<Vec<u8>> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buffer: Vec<u8> = Vec::new();
+    loop {
+        let len = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                buffer.clear();
+                continue;
+            }
+            return Err(e);
+        }
+        if buffer.is_empty() && len > 0 {
+            buffer.resize(len as usize, 0);
+            continue;
+        }
+        buffer.truncate(len as usize);
+        return Ok(buffer);
+    }
+}
+
+fn set_xattr(path: &CString, name: &[u8], value: &[u8]) -> io::Result<()> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let result = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        path::PathBuf,
+    };
+
+    use super::{cpath, get_xattr, preserve, set_times, set_xattr, xattr_names};
+
+    #[test]
+    fn preserves_mode_times_and_xattrs() {
+        let dir = PathBuf::from("test_metadata_preserves");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let source = dir.join("source");
+        let target = dir.join("target");
+        fs::write(&source, "contents").unwrap();
+        fs::write(&target, "contents").unwrap();
+
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o4751)).unwrap();
+        let source_c = cpath(&source).unwrap();
+        let xattr_supported = set_xattr(&source_c, b"user.relocation", b"value").is_ok();
+        let old = fs::metadata("Cargo.toml").unwrap();
+        set_times(&source_c, &old).unwrap();
+
+        let unpreserved = preserve(&source, &target).unwrap();
+        assert!(unpreserved.is_empty(), "{unpreserved:?}");
+
+        let metadata = fs::metadata(&target).unwrap();
+        assert_eq!(0o4751, metadata.mode() & 0o7777);
+        assert_eq!(old.mtime(), metadata.mtime());
+        assert_eq!(old.mtime_nsec(), metadata.mtime_nsec());
+        assert_eq!(old.atime(), metadata.atime());
+        if xattr_supported {
+            let target_c = cpath(&target).unwrap();
+            assert!(xattr_names(&target_c)
+                .unwrap()
+                .contains(&b"user.relocation".to_vec()));
+            assert_eq!(
+                b"value".to_vec(),
+                get_xattr(&target_c, b"user.relocation").unwrap()
+            );
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,9 @@
+mod executor;
+mod journal;
+mod metadata;
+mod verify;
+
+pub use executor::{Executor, MoveError, Report};
+pub use journal::{Journal, Stage};
+pub use metadata::{Attribute, Unpreserved};
+pub use verify::HashAlgorithm;
@@ -1,11 +1,83 @@
-use std::path::Path;
+use std::{fmt, os::unix::fs::MetadataExt, path::Path, str::FromStr};
 
+use serde::{Deserialize, Serialize};
+
+/// Space to leave free on a filesystem, whatever is moved onto it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Reserve {
+    Bytes(u64),
+    /// Basis points (hundredths of a percent) of capacity.
+    BasisPoints(u64),
+}
+
+/// A [`Reserve`] which could not be parsed.
 #[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ReserveError(String);
+
+impl fmt::Display for ReserveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid reserve {:?}: expected bytes, with an optional K, M, G or T suffix, or a percentage",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ReserveError {}
+
+impl FromStr for Reserve {
+    type Err = ReserveError;
+
+    /// Parse `10G`, `512M`, `1048576` or `5%` (say); suffixes are powers of 1024.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ReserveError(s.to_string());
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent = percent.trim().parse::<f64>().map_err(|_| error())?;
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(error());
+            }
+            return Ok(Reserve::BasisPoints((percent * 100.0).round() as u64));
+        }
+        let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let scale = match s[digits.len()..].to_ascii_uppercase().as_str() {
+            "" | "B" => 1,
+            "K" | "KB" | "KIB" => 1 << 10,
+            "M" | "MB" | "MIB" => 1 << 20,
+            "G" | "GB" | "GIB" => 1 << 30,
+            "T" | "TB" | "TIB" => 1 << 40,
+            _ => return Err(error()),
+        };
+        let bytes = digits.trim().parse::<u64>().map_err(|_| error())?;
+        bytes
+            .checked_mul(scale)
+            .map(Reserve::Bytes)
+            .ok_or_else(error)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct FileSystem {
-    id: u64,
-    block_size: u64,
+    /// Device the root is on: roots on the same one can be joined by renames.
+    pub(crate) id: u64,
+    pub(crate) block_size: u64,
     pub(crate) blocks_available: u64,
-    scratch: bool,
+    /// Capacity, or 0 if unknown.
+    #[serde(default)]
+    pub(crate) blocks_total: u64,
+    /// Inodes free, where the filesystem limits them.
+    #[serde(default)]
+    pub(crate) inodes_available: u64,
+    /// Inodes in all, or 0 if the filesystem does not limit them.
+    #[serde(default)]
+    pub(crate) inodes_total: u64,
+    /// Blocks which must be left available.
+    #[serde(default)]
+    pub(crate) reserved_blocks: u64,
+    pub(crate) scratch: bool,
+    /// Being retired: everything must move off it, and nothing onto it.
+    #[serde(default)]
+    pub(crate) drain: bool,
 }
 
 impl FileSystem {
@@ -14,9 +86,63 @@ impl FileSystem {
             id,
             block_size,
             blocks_available,
+            blocks_total: 0,
+            inodes_available: 0,
+            inodes_total: 0,
+            reserved_blocks: 0,
             scratch,
+            drain: false,
         }
     }
+
+    pub fn with_blocks_total(mut self, blocks_total: u64) -> Self {
+        self.blocks_total = blocks_total;
+        self
+    }
+
+    pub fn with_inodes(mut self, inodes_available: u64, inodes_total: u64) -> Self {
+        self.inodes_available = inodes_available;
+        self.inodes_total = inodes_total;
+        self
+    }
+
+    /// Leave `reserve` free; a percentage of an unknown capacity reserves nothing.
+    pub fn with_reserve(mut self, reserve: Reserve) -> Self {
+        self.reserved_blocks = match reserve {
+            Reserve::Bytes(bytes) => bytes.div_ceil(self.block_size),
+            Reserve::BasisPoints(basis_points) => {
+                (self.blocks_total as u128 * basis_points as u128).div_ceil(10_000) as u64
+            }
+        };
+        self
+    }
+
+    pub fn with_drain(mut self, drain: bool) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    /// Whether files may end up here, rather than only pass through or leave.
+    pub fn keeps_files(&self) -> bool {
+        !self.scratch && !self.drain
+    }
+
+    /// Whether a file of `size` bytes fits, leaving the reserve free.
+    pub fn has_room(&self, size: u64) -> bool {
+        self.has_blocks(self.blocks(size))
+    }
+
+    /// Whether `blocks` more blocks can be consumed, leaving the reserve free.
+    pub fn has_blocks(&self, blocks: u64) -> bool {
+        self.blocks_available >= self.reserved_blocks.saturating_add(blocks)
+    }
+
+    /// Whether `inodes` more files can be created without exhausting the inodes, as far
+    /// as is known: one is always left to spare.
+    pub fn has_inodes(&self, inodes: u64) -> bool {
+        self.inodes_total == 0 || self.inodes_available > inodes
+    }
+
     pub fn blocks(&self, size: u64) -> u64 {
         1 + (size / self.block_size)
     }
@@ -28,6 +154,24 @@ impl FileSystem {
     pub fn free_bytes(&self) -> u64 {
         self.block_size.saturating_mul(self.blocks_available)
     }
+
+    /// Free space beyond the reserve.
+    pub fn usable_bytes(&self) -> u64 {
+        self.block_size
+            .saturating_mul(self.blocks_available.saturating_sub(self.reserved_blocks))
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.block_size.saturating_mul(self.blocks_total)
+    }
+
+    /// Free space in basis points (hundredths of a percent) of capacity, if known.
+    pub fn free_basis_points(&self) -> Option<u64> {
+        if self.blocks_total == 0 {
+            return None;
+        }
+        Some((self.blocks_available as u128 * 10_000 / self.blocks_total as u128) as u64)
+    }
 }
 
 impl FileSystem {
@@ -40,22 +184,67 @@ impl FileSystem {
         cpath
     }
 
-    fn stats(mount_point: &Path) -> Option<(u64, u64, u64)> {
+    /// Device, block size, blocks available and in all, and inodes available and in all.
+    ///
+    /// The device (`st_dev`), not the filesystem id, tells which roots a rename can
+    /// join: some filesystems give every mount the same id.
+    pub(crate) fn stats(mount_point: &Path) -> std::io::Result<(u64, u64, u64, u64, u64, u64)> {
+        let dev = std::fs::metadata(mount_point)?.dev();
         unsafe {
             let mut stat: libc::statvfs = std::mem::zeroed();
             let mount_point_cpath = Self::to_cpath(mount_point);
             if libc::statvfs(mount_point_cpath.as_ptr() as *const _, &mut stat) == 0 {
-                Some((stat.f_fsid, stat.f_bsize, stat.f_bavail))
+                Ok((
+                    dev,
+                    stat.f_bsize,
+                    stat.f_bavail,
+                    stat.f_blocks,
+                    stat.f_favail,
+                    stat.f_files,
+                ))
             } else {
-                None
+                Err(std::io::Error::last_os_error())
             }
         }
     }
 }
 
-impl From<(&Path, bool)> for FileSystem {
-    fn from((root, is_scratchpad): (&Path, bool)) -> Self {
-        let (fsid, bsize, bavail) = Self::stats(root).unwrap();
-        Self::new(fsid, bsize, bavail, is_scratchpad)
+impl TryFrom<(&Path, bool)> for FileSystem {
+    type Error = std::io::Error;
+
+    fn try_from((root, is_scratchpad): (&Path, bool)) -> Result<Self, Self::Error> {
+        let (dev, bsize, bavail, blocks, favail, files) = Self::stats(root)?;
+        Ok(Self::new(dev, bsize, bavail, is_scratchpad)
+            .with_blocks_total(blocks)
+            .with_inodes(favail, files))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileSystem, Reserve};
+
+    #[test]
+    fn parse_reserve() {
+        assert_eq!(Ok(Reserve::Bytes(1234)), "1234".parse());
+        assert_eq!(Ok(Reserve::Bytes(10 << 30)), "10G".parse());
+        assert_eq!(Ok(Reserve::Bytes(512 << 20)), "512MiB".parse());
+        assert_eq!(Ok(Reserve::BasisPoints(250)), "2.5%".parse());
+        assert!("5X".parse::<Reserve>().is_err());
+        assert!("101%".parse::<Reserve>().is_err());
+        assert!("G".parse::<Reserve>().is_err());
+    }
+
+    #[test]
+    fn reserve_is_left_free() {
+        let fs = FileSystem::new(1, 4096, 100, false).with_blocks_total(1000);
+        assert!(fs.has_room(99 * 4096));
+        let fs = fs.with_reserve(Reserve::BasisPoints(500));
+        assert_eq!(50, fs.reserved_blocks);
+        assert!(fs.has_room(49 * 4096));
+        assert!(!fs.has_room(50 * 4096));
+        assert_eq!(50 * 4096, fs.usable_bytes());
+        let fs = fs.with_reserve(Reserve::Bytes(4097));
+        assert_eq!(2, fs.reserved_blocks);
     }
 }
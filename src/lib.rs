@@ -1,16 +1,237 @@
 use chrono::Local;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use env_logger::{Builder, Env};
-use std::io::Write;
+use regex::Regex;
+use std::{ffi::OsString, io::Write, path::PathBuf, time::Duration};
 
 #[derive(Debug, Clone, Parser)]
 #[clap(author, version, about, long_about = None)]
 pub struct Config {
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Plan the relocation of files within the given roots.
+    Plan(Box<PlanConfig>),
+    /// Apply a previously written plan.
+    Apply(ApplyConfig),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct PlanConfig {
     /// Path(s) to search for files within.
     pub root: Vec<String>,
+    /// Path(s) usable only to stage files temporarily; they must end empty.
+    #[clap(long)]
+    pub scratch: Vec<String>,
+    /// Path(s) being retired: every file must move off them, onto the other roots.
+    #[clap(long)]
+    pub drain: Vec<String>,
+    /// Number of leading path components below each root which must be kept together.
+    #[clap(long, value_name = "N", group = "grouping")]
+    pub group_depth: Option<usize>,
+    /// Keep together each outermost directory containing a file of this name.
+    #[clap(long, value_name = "NAME", group = "grouping", min_values = 0,
+        max_values = 1, require_equals = true, default_missing_value = DEFAULT_MARKER)]
+    pub group_marker: Option<OsString>,
+    /// Keep together paths (below each root) matching this pattern, up to the end of its first capture group.
+    #[clap(long, value_name = "REGEX", group = "grouping", value_parser = Regex::new)]
+    pub group_regex: Option<Regex>,
+    /// Gitignore-style pattern of paths within each root to leave alone.
+    #[clap(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+    /// Gitignore-style pattern of paths to scan even if excluded.
+    #[clap(long, value_name = "PATTERN")]
+    pub include: Vec<String>,
+    /// Read further exclusions from a file of this name at the top of each root, if present.
+    #[clap(
+        long,
+        value_name = "NAME",
+        min_values = 0,
+        max_values = 1,
+        require_equals = true,
+        default_missing_value = DEFAULT_IGNORE_FILE
+    )]
+    pub ignore_file: Option<PathBuf>,
+    /// What becomes of symbolic links moved to another root.
+    #[clap(long, value_enum, default_value = "preserve")]
+    pub symlinks: SymlinkPolicy,
+    /// Space to leave free on every root: bytes, with an optional K, M, G or T suffix, or a percentage of capacity.
+    #[clap(long, value_name = "SIZE")]
+    pub reserve: Option<Reserve>,
+    /// Space to leave free on one root, overriding --reserve.
+    #[clap(long, value_name = "ROOT=SIZE", value_parser = parse_root_reserve)]
+    pub root_reserve: Vec<(String, Reserve)>,
+    /// Leave directories emptied by the moves in place, rather than removing them.
+    #[clap(long)]
+    pub keep_source_dirs: bool,
+    /// Keep everything below SUBDIR on ROOT, gathering it there if need be.
+    #[clap(long, value_name = "SUBDIR=ROOT", value_parser = parse_placement)]
+    pub pin: Vec<(PathBuf, PathBuf)>,
+    /// Never leave anything below SUBDIR on ROOT.
+    #[clap(long, value_name = "SUBDIR=ROOT", value_parser = parse_placement)]
+    pub forbid: Vec<(PathBuf, PathBuf)>,
+    /// Root(s) onto which nothing may be moved; what is already there may stay.
+    #[clap(long, value_name = "ROOT")]
+    pub no_target: Vec<PathBuf>,
+    /// Read further placement constraints from this file.
+    #[clap(long, value_name = "FILE")]
+    pub constraints: Option<PathBuf>,
+    /// How files are relocated between filesystems.
+    #[clap(long, value_enum, default_value = "ida-star")]
+    pub planner: Strategy,
+    /// Search for all subdirs together, rather than each in turn, as the exact planner always does.
+    #[clap(long)]
+    pub joint: bool,
+    /// Number of states a beam search keeps at each depth.
+    #[clap(long, value_name = "N", default_value_t = DEFAULT_BEAM_WIDTH)]
+    pub beam_width: usize,
+    /// Seconds a search may take before settling for the best partial plan found.
+    #[clap(long, value_name = "SECONDS")]
+    pub time_limit: Option<f64>,
+    /// Number of states a search may expand before settling for the best partial plan found.
+    #[clap(long, value_name = "N")]
+    pub node_limit: Option<u64>,
+    /// Also even out free space, to within this many percent across filesystems.
+    #[clap(long, value_name = "TOLERANCE", value_parser = parse_tolerance)]
+    pub rebalance: Option<u64>,
+    /// File to write the plan to, instead of standard output.
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+    /// Form in which to write the plan.
+    #[clap(long, value_enum, default_value = "json")]
+    pub format: PlanFormat,
     /// Should plan be executed
     #[clap(long)]
     pub execute: bool,
+    #[clap(flatten)]
+    pub execution: ExecuteConfig,
+}
+
+impl PlanConfig {
+    /// The grouping chosen by the --group-* options, if any.
+    pub fn grouping(&self) -> Option<Grouping> {
+        if let Some(depth) = self.group_depth {
+            return Some(Grouping::Depth(depth));
+        }
+        if let Some(marker) = &self.group_marker {
+            return Some(Grouping::Marker(marker.clone()));
+        }
+        self.group_regex.clone().map(Grouping::Regex)
+    }
+
+    /// The filter built from the --exclude, --include and --ignore-file options.
+    pub fn filter(&self) -> Filter {
+        let mut filter = Filter::default();
+        if let Some(ignore_file) = &self.ignore_file {
+            filter = filter.ignore_file(ignore_file);
+        }
+        for pattern in &self.exclude {
+            filter = filter.exclude(pattern);
+        }
+        for pattern in &self.include {
+            filter = filter.include(pattern);
+        }
+        filter
+    }
+
+    /// The planner chosen by the --planner, --joint, --beam-width and budget options.
+    pub fn planner(&self) -> Box<dyn Planner> {
+        let planner = self.planner.planner(self.beam_width, self.budget());
+        // Branch and bound already weighs every subdir at once, and bounds only the whole
+        if self.joint || self.planner == Strategy::Exact {
+            planner
+        } else {
+            Box::new(Decompose(planner))
+        }
+    }
+
+    /// The search budget set by the --time-limit and --node-limit options.
+    pub fn budget(&self) -> Budget {
+        let mut budget = Budget::default();
+        if let Some(seconds) = self.time_limit {
+            budget = budget.time(Duration::from_secs_f64(seconds));
+        }
+        if let Some(nodes) = self.node_limit {
+            budget = budget.nodes(nodes);
+        }
+        budget
+    }
+
+    /// The constraints read from --constraints, then those of the --pin, --forbid and --no-target options.
+    pub fn constraints(&self) -> std::io::Result<Constraints> {
+        let mut constraints = match &self.constraints {
+            Some(path) => Constraints::load(path)?,
+            None => Constraints::default(),
+        };
+        for (subdir, root) in &self.pin {
+            constraints = constraints.pin(subdir, root);
+        }
+        for (subdir, root) in &self.forbid {
+            constraints = constraints.forbid(subdir, root);
+        }
+        for root in &self.no_target {
+            constraints = constraints.no_target(root);
+        }
+        Ok(constraints)
+    }
+}
+
+fn parse_root_reserve(s: &str) -> Result<(String, Reserve), String> {
+    let (root, reserve) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected ROOT=SIZE, not {s:?}"))?;
+    Ok((
+        root.to_string(),
+        reserve.parse().map_err(|e| format!("{e}"))?,
+    ))
+}
+
+/// Parse a percentage from 0 to 100 into basis points.
+fn parse_tolerance(s: &str) -> Result<u64, String> {
+    let percent = s
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("expected a percentage, not {s:?}"))?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("expected a percentage from 0 to 100, not {s:?}"));
+    }
+    Ok((percent * 100.0).round() as u64)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, ValueEnum)]
+pub enum PlanFormat {
+    /// For review, and `relocation apply`.
+    Json,
+    /// A standalone POSIX shell script.
+    Sh,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ApplyConfig {
+    /// Plan written by `relocation plan`.
+    #[clap(required_unless_present = "resume")]
+    pub plan: Option<PathBuf>,
+    /// Finish the plan recorded in an interrupted journal, instead of applying afresh.
+    #[clap(long, conflicts_with_all = &["plan", "journal"])]
+    pub resume: Option<PathBuf>,
+    #[clap(flatten)]
+    pub execution: ExecuteConfig,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ExecuteConfig {
+    /// Checksum each cross-filesystem copy, keeping the source unless it matches.
+    #[clap(long)]
+    pub verify: bool,
+    /// Checksum algorithm used by --verify.
+    #[clap(long, value_enum, default_value = "sha256")]
+    pub hash: HashAlgorithm,
+    /// Journal recording the progress of an executed plan.
+    #[clap(long)]
+    pub journal: Option<PathBuf>,
 }
 
 pub fn setup_logger(is_test: bool) {
@@ -29,7 +250,20 @@ pub fn setup_logger(is_test: bool) {
         .init();
 }
 
+mod execute;
 mod filesystem;
+mod plan;
+mod script;
 mod state;
 
-pub use state::{Entry, Move, State};
+pub use execute::{
+    Attribute, Executor, HashAlgorithm, Journal, MoveError, Report, Stage, Unpreserved,
+};
+pub use filesystem::{FileSystem, Reserve, ReserveError};
+pub use plan::{Mismatch, Plan, Root};
+use state::parse_placement;
+pub use state::{
+    AStar, Allowance, Beam, Bounded, BranchAndBound, Budget, Constraints, Decompose, Entry, Filter,
+    Greedy, Grouping, IdaStar, Move, Objective, Planner, ScanError, SkipReason, Skipped, State,
+    Strategy, SymlinkPolicy, DEFAULT_BEAM_WIDTH, DEFAULT_IGNORE_FILE, DEFAULT_MARKER,
+};
@@ -1,9 +1,17 @@
 extern crate env_logger;
 extern crate log;
 
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
 use clap::StructOpt;
-use log::debug;
-use relocation::State;
+use log::{debug, error, info, warn};
+use relocation::{
+    ApplyConfig, Command, ExecuteConfig, Executor, Objective, Plan, PlanConfig, PlanFormat, Report,
+    State,
+};
 
 use relocation::{setup_logger, Config};
 
@@ -12,18 +20,140 @@ fn main() -> Result<(), std::io::Error> {
 
     setup_logger(false);
 
+    match &config.command {
+        Command::Plan(config) => plan(config),
+        Command::Apply(config) => apply(config),
+    }
+}
+
+fn plan(config: &PlanConfig) -> Result<(), std::io::Error> {
     let mut initial = State::default();
+    if let Some(grouping) = config.grouping() {
+        initial.set_grouping(grouping);
+    }
+    initial.set_filter(config.filter());
+    initial.set_symlink_policy(config.symlinks);
+    initial.set_keep_source_dirs(config.keep_source_dirs);
+    let mut skipped = Vec::new();
     for root in &config.root {
-        initial += root;
+        skipped.extend(initial.try_add_root(root).map_err(io::Error::other)?);
+    }
+    for scratch in &config.scratch {
+        skipped.extend(initial.try_add_scratch(scratch).map_err(io::Error::other)?);
+    }
+    for drained in &config.drain {
+        skipped.extend(initial.try_add_drained(drained).map_err(io::Error::other)?);
+    }
+    if !skipped.is_empty() {
+        warn!("{} paths skipped while scanning", skipped.len());
+        for skipped in &skipped {
+            warn!("  {:?}: {}", skipped.path, skipped.reason);
+        }
+    }
+    if let Some(reserve) = config.reserve {
+        initial.set_reserve(reserve);
+    }
+    for (root, reserve) in &config.root_reserve {
+        initial.set_root_reserve(root, *reserve)?;
+    }
+    initial.set_constraints(config.constraints()?)?;
+    if let Some(tolerance) = config.rebalance {
+        initial.set_objective(Objective::Rebalance { tolerance });
     }
 
     debug!("initially: {initial:#?}");
 
-    let (moves, _cost) = initial.relocate().unwrap_or_default();
-    if config.execute {
-        for m in moves {
-            println!("Move {:?} to {:?}", m.source, m.target);
+    let plan = initial
+        .plan_with(&config.planner())
+        .ok_or_else(|| std::io::Error::other("no relocation found"))?;
+    if !plan.split.is_empty() {
+        warn!("Partial plan: {} subdirs remain split", plan.split.len());
+        for subdir in &plan.split {
+            warn!("  {:?}", subdir);
         }
     }
+    if !plan.misplaced.is_empty() {
+        warn!(
+            "Partial plan: {} subdirs remain on roots they may not end on",
+            plan.misplaced.len()
+        );
+        for subdir in &plan.misplaced {
+            warn!("  {:?}", subdir);
+        }
+    }
+    if let Some(lower_bound) = plan.lower_bound.filter(|bound| *bound < plan.cost) {
+        warn!(
+            "Plan may cost up to {} more than the cheapest",
+            plan.cost - lower_bound
+        );
+    }
+    let mut writer: Box<dyn Write> = match &config.output {
+        Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    match config.format {
+        PlanFormat::Json => plan.write(&mut writer)?,
+        PlanFormat::Sh => plan.write_script(&mut writer)?,
+    }
+    writer.flush()?;
+    if let Some(output) = &config.output {
+        info!("Plan of {} moves written to {:?}", plan.moves.len(), output);
+    }
+    if config.execute {
+        let report = executor(&config.execution).execute(&plan.moves)?;
+        return summarise(&report);
+    }
     Ok(())
 }
+
+fn apply(config: &ApplyConfig) -> Result<(), std::io::Error> {
+    let executor = executor(&config.execution);
+    if let Some(journal) = &config.resume {
+        let report = executor.resume(journal)?;
+        return summarise(&report);
+    }
+    let path = config.plan.as_ref().expect("plan or journal required");
+    let plan = Plan::load(path)?;
+    let mismatches = plan.mismatches();
+    if !mismatches.is_empty() {
+        for mismatch in &mismatches {
+            error!("  {}", mismatch);
+        }
+        return Err(std::io::Error::other(format!(
+            "filesystems no longer match {path:?}"
+        )));
+    }
+    let report = executor.execute(&plan.moves)?;
+    summarise(&report)
+}
+
+fn executor(config: &ExecuteConfig) -> Executor {
+    let mut executor = Executor::default();
+    if config.verify {
+        executor = executor.verify(config.hash);
+    }
+    if let Some(journal) = &config.journal {
+        executor = executor.journal(journal);
+    }
+    executor
+}
+
+fn summarise(report: &Report) -> Result<(), std::io::Error> {
+    if !report.unpreserved.is_empty() {
+        warn!("{} attributes not preserved", report.unpreserved.len());
+        for unpreserved in &report.unpreserved {
+            warn!(
+                "  {} of {:?}: {}",
+                unpreserved.attribute, unpreserved.path, unpreserved.error
+            );
+        }
+    }
+    if report.failures.is_empty() {
+        return Ok(());
+    }
+    error!("{} moves failed", report.failures.len());
+    for failure in &report.failures {
+        error!("  {:?}: {}", failure.failed, failure.error);
+    }
+    Err(std::io::Error::other("relocation incomplete"))
+}
@@ -0,0 +1,582 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{filesystem::FileSystem, Decompose, IdaStar, Move, Planner, State};
+
+/// A scan root, with its filesystem as it was when planned.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Root {
+    #[serde(with = "serde_path")]
+    pub path: PathBuf,
+    pub filesystem: FileSystem,
+}
+
+/// A relocation to be reviewed and applied later.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Plan {
+    pub roots: Vec<Root>,
+    pub moves: Vec<Move>,
+    pub cost: u64,
+    /// Subdirs the moves leave split across roots, as a search cut short by its budget may.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "serde_path::vec"
+    )]
+    pub split: Vec<PathBuf>,
+    /// Subdirs the moves leave wholly on a root they may not end on: a scratch or drained
+    /// root, or one their constraints rule out.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "serde_path::vec"
+    )]
+    pub misplaced: Vec<PathBuf>,
+    /// Least cost any relocation could have, where the planner proves one: the cost
+    /// beyond it is how far the plan may be from the cheapest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lower_bound: Option<u64>,
+}
+
+/// A way in which the live filesystems no longer match a plan.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Mismatch {
+    Unavailable(PathBuf),
+    DifferentFilesystem(PathBuf),
+    MissingSource(PathBuf),
+    TargetExists(PathBuf),
+    InsufficientSpace {
+        root: PathBuf,
+        required: u64,
+        available: u64,
+    },
+    InsufficientInodes {
+        root: PathBuf,
+        required: u64,
+        available: u64,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Unavailable(root) => write!(f, "{root:?} is unavailable"),
+            Mismatch::DifferentFilesystem(root) => {
+                write!(f, "{root:?} is on a different filesystem")
+            }
+            Mismatch::MissingSource(source) => write!(f, "{source:?} no longer exists"),
+            Mismatch::TargetExists(target) => write!(f, "{target:?} already exists"),
+            Mismatch::InsufficientSpace {
+                root,
+                required,
+                available,
+            } => write!(
+                f,
+                "{root:?} has {available} bytes free beyond its reserve, {required} required"
+            ),
+            Mismatch::InsufficientInodes {
+                root,
+                required,
+                available,
+            } => write!(
+                f,
+                "{root:?} has {available} inodes free, {required} required and one to spare"
+            ),
+        }
+    }
+}
+
+impl State {
+    /// Plan the relocation of this state's entries, and of the directories this empties,
+    /// with the default planner, [`IdaStar`] for each subdir in turn.
+    ///
+    /// Returns `None` if no relocation could be found, complete or, where the planner's
+    /// budget ran out, partial.
+    pub fn plan(&self) -> Option<Plan> {
+        self.plan_with(&Decompose(IdaStar::default()))
+    }
+
+    /// Plan as [`State::plan`], relocating with `planner`.
+    pub fn plan_with(&self, planner: &dyn Planner) -> Option<Plan> {
+        let (mut moves, cost, lower_bound) = if self.success() {
+            info!("Already fully relocated");
+            (Vec::new(), 0, None)
+        } else {
+            self.relocate_bounded_with(planner)?
+        };
+        let (split, misplaced) = self.unsettled_after(&moves);
+        moves.extend(self.directory_moves(&moves));
+        let mut roots = self
+            .roots
+            .iter()
+            .map(|(path, filesystem)| Root {
+                path: path.clone(),
+                filesystem: filesystem.clone(),
+            })
+            .collect::<Vec<_>>();
+        roots.sort_by(|a, b| a.path.cmp(&b.path));
+        Some(Plan {
+            roots,
+            moves,
+            cost,
+            split,
+            misplaced,
+            lower_bound,
+        })
+    }
+
+    /// The subdirs with files on more than one root once `moves` are made, and those
+    /// wholly on a root they may not end on.
+    fn unsettled_after(&self, moves: &[Move]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let (mut split, mut misplaced) = (Vec::new(), Vec::new());
+        for (subdir, roots) in self.roots_after(moves) {
+            if roots.len() > 1 {
+                split.push(subdir.to_path_buf());
+            } else if roots.iter().any(|root| {
+                !self.roots[*root].keeps_files() || !self.scan.constraints.may_hold(subdir, root)
+            }) {
+                misplaced.push(subdir.to_path_buf());
+            }
+        }
+        split.sort();
+        misplaced.sort();
+        (split, misplaced)
+    }
+
+    /// The roots each subdir has files on once `moves` are made.
+    pub(crate) fn roots_after(&self, moves: &[Move]) -> HashMap<&Path, HashSet<&Path>> {
+        let mut locations = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (entry.root.join(entry.path()), index))
+            .collect::<HashMap<_, _>>();
+        for m in moves.iter().filter(|m| m.hard_link.is_none()) {
+            if let Some(index) = locations.remove(&m.source) {
+                locations.insert(m.target.clone(), index);
+                continue;
+            }
+            // A directory renamed whole, as by a merge, carries every file within it
+            let within = locations
+                .keys()
+                .filter(|location| location.starts_with(&m.source))
+                .cloned()
+                .collect::<Vec<_>>();
+            for location in within {
+                if let (Some(index), Ok(rest)) = (
+                    locations.remove(&location),
+                    location.strip_prefix(&m.source),
+                ) {
+                    locations.insert(m.target.join(rest), index);
+                }
+            }
+        }
+        let mut roots = HashMap::<&Path, HashSet<&Path>>::new();
+        for (location, index) in &locations {
+            let entry = &self.entries[*index];
+            let root = self
+                .roots
+                .keys()
+                .find(|root| root.join(entry.path()) == *location)
+                .unwrap_or(&entry.root);
+            roots.entry(&entry.subdir).or_default().insert(root);
+        }
+        roots
+    }
+}
+
+impl Plan {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)
+    }
+
+    /// Compare the plan against the live filesystems.
+    ///
+    /// Each root must still be on the same filesystem, each source must still
+    /// exist (and its target not), and each root must have room, beyond its
+    /// reserve, for the most data the moves place on it at any one time, and
+    /// inodes for the most files and directories they make there.
+    pub fn mismatches(&self) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        let mut live = HashMap::new();
+        for root in &self.roots {
+            match FileSystem::stats(&root.path) {
+                Ok((id, block_size, ..))
+                    if id != root.filesystem.id || block_size != root.filesystem.block_size =>
+                {
+                    mismatches.push(Mismatch::DifferentFilesystem(root.path.clone()))
+                }
+                Ok((
+                    id,
+                    block_size,
+                    blocks_available,
+                    blocks_total,
+                    inodes_available,
+                    inodes_total,
+                )) => {
+                    let filesystem =
+                        FileSystem::new(id, block_size, blocks_available, root.filesystem.scratch)
+                            .with_blocks_total(blocks_total)
+                            .with_inodes(inodes_available, inodes_total);
+                    let filesystem = FileSystem {
+                        reserved_blocks: root.filesystem.reserved_blocks,
+                        ..filesystem
+                    };
+                    live.insert(&root.path, filesystem);
+                }
+                Err(_) => mismatches.push(Mismatch::Unavailable(root.path.clone())),
+            }
+        }
+        // Net bytes and inodes placed on each root, as the moves are made in turn
+        let mut usage = HashMap::<&PathBuf, (i128, i128)>::new();
+        let mut inodes = HashMap::<&PathBuf, (i64, i64)>::new();
+        let mut made = HashSet::new();
+        for m in &self.moves {
+            let metadata = match fs::symlink_metadata(&m.source) {
+                Ok(metadata) => metadata,
+                Err(_) => {
+                    mismatches.push(Mismatch::MissingSource(m.source.clone()));
+                    continue;
+                }
+            };
+            // A directory's target may well exist already
+            if !m.directory && fs::symlink_metadata(&m.target).is_ok() {
+                mismatches.push(Mismatch::TargetExists(m.target.clone()));
+            }
+            let (source_root, target_root) =
+                match (self.root_of(&m.source), self.root_of(&m.target)) {
+                    (Some(source_root), Some(target_root)) => {
+                        (&source_root.path, &target_root.path)
+                    }
+                    _ => continue,
+                };
+            let (source_fs, target_fs) = match (live.get(source_root), live.get(target_root)) {
+                (Some(source_fs), Some(target_fs)) => (source_fs, target_fs),
+                _ => continue,
+            };
+            if source_fs.id == target_fs.id {
+                // A rename needs no space
+                continue;
+            }
+            // Directories missing on the target are made for the move
+            let within = if m.directory {
+                Some(m.target.as_path())
+            } else {
+                m.target.parent()
+            };
+            let directories = within
+                .into_iter()
+                .flat_map(Path::ancestors)
+                .take_while(|dir| *dir != target_root && dir.starts_with(target_root))
+                .filter(|dir| fs::symlink_metadata(dir).is_err() && made.insert(dir.to_path_buf()))
+                .count() as i64;
+            let file = i64::from(!m.directory && m.hard_link.is_none());
+            let (net, peak) = inodes.entry(target_root).or_default();
+            *net += directories + file;
+            *peak = (*peak).max(*net);
+            // The source's inode is only freed with its last link
+            if m.directory || metadata.nlink() == 1 {
+                inodes.entry(source_root).or_default().0 -= 1;
+            }
+            if m.directory {
+                // It takes up no space
+                continue;
+            }
+            if m.hard_link.is_some() {
+                // Nor does a further link, though the source's space is only freed with the last
+                continue;
+            }
+            let size = metadata.len();
+            let (net, peak) = usage.entry(target_root).or_default();
+            *net += target_fs.effective_size(size) as i128;
+            *peak = (*peak).max(*net);
+            usage.entry(source_root).or_default().0 -= source_fs.effective_size(size) as i128;
+        }
+        for (root, (_, peak)) in usage {
+            let available = live[root].usable_bytes();
+            if peak > available as i128 {
+                mismatches.push(Mismatch::InsufficientSpace {
+                    root: root.clone(),
+                    required: peak as u64,
+                    available,
+                });
+            }
+        }
+        for (root, (_, peak)) in inodes {
+            let fs = &live[root];
+            if peak > 0 && !fs.has_inodes(peak as u64) {
+                mismatches.push(Mismatch::InsufficientInodes {
+                    root: root.clone(),
+                    required: peak as u64,
+                    available: fs.inodes_available,
+                });
+            }
+        }
+        mismatches
+    }
+
+    /// The (innermost) root containing `path`.
+    pub(crate) fn root_of(&self, path: &Path) -> Option<&Root> {
+        let root = innermost_root(self.roots.iter().map(|root| &root.path), path)?;
+        self.roots.iter().find(|candidate| candidate.path == *root)
+    }
+}
+
+/// The innermost of `roots` containing `path`.
+pub(crate) fn innermost_root<'a>(
+    roots: impl Iterator<Item = &'a PathBuf>,
+    path: &Path,
+) -> Option<&'a PathBuf> {
+    roots
+        .filter(|root| path.starts_with(root))
+        .max_by_key(|root| root.components().count())
+}
+
+/// Paths as JSON strings, falling back to an array of bytes for those which are not UTF-8.
+pub(crate) mod serde_path {
+    use std::{
+        ffi::OsString,
+        os::unix::ffi::{OsStrExt, OsStringExt},
+        path::{Path, PathBuf},
+    };
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Text(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(text) => serializer.serialize_str(text),
+            None => serializer.serialize_bytes(path.as_os_str().as_bytes()),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Text(text) => PathBuf::from(text),
+            Repr::Bytes(bytes) => PathBuf::from(OsString::from_vec(bytes)),
+        })
+    }
+
+    /// As the enclosing module, for optional paths.
+    pub mod option {
+        use std::path::{Path, PathBuf};
+
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        #[derive(Serialize)]
+        struct Borrowed<'a>(#[serde(with = "super")] &'a Path);
+
+        #[derive(Deserialize)]
+        struct Owned(#[serde(with = "super")] PathBuf);
+
+        pub fn serialize<S: Serializer>(
+            path: &Option<PathBuf>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            path.as_deref().map(Borrowed).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<PathBuf>, D::Error> {
+            Ok(Option::<Owned>::deserialize(deserializer)?.map(|Owned(path)| path))
+        }
+    }
+
+    /// As the enclosing module, for lists of paths.
+    pub mod vec {
+        use std::path::{Path, PathBuf};
+
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        #[derive(Serialize)]
+        struct Borrowed<'a>(#[serde(with = "super")] &'a Path);
+
+        #[derive(Deserialize)]
+        struct Owned(#[serde(with = "super")] PathBuf);
+
+        pub fn serialize<S: Serializer>(
+            paths: &[PathBuf],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(paths.iter().map(|path| Borrowed(path)))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<PathBuf>, D::Error> {
+            Ok(Vec::<Owned>::deserialize(deserializer)?
+                .into_iter()
+                .map(|Owned(path)| path)
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::PathBuf, sync::Arc};
+
+    use super::{Plan, Root};
+    use crate::{filesystem::FileSystem, state::fixture, Constraints, Move};
+
+    #[test]
+    fn json_round_trip() {
+        let plan = Plan {
+            roots: vec![Root {
+                path: PathBuf::from("/a"),
+                filesystem: FileSystem::new(1, 4096, 100, false),
+            }],
+            moves: vec![
+                Move {
+                    source: PathBuf::from("/a/c/1.txt"),
+                    target: PathBuf::from(OsStr::from_bytes(b"/b/c/\xff.txt")),
+                    ..Default::default()
+                },
+                Move {
+                    source: PathBuf::from("/a/c/2.txt"),
+                    target: PathBuf::from("/b/c/2.txt"),
+                    hard_link: Some(PathBuf::from(OsStr::from_bytes(b"/b/c/\xff.txt"))),
+                    symlink: Some(PathBuf::from("/b/c/1.txt")),
+                    ..Default::default()
+                },
+                Move {
+                    source: PathBuf::from("/a/c"),
+                    target: PathBuf::from("/b/c"),
+                    directory: true,
+                    ..Default::default()
+                },
+            ],
+            cost: 4096,
+            split: vec![
+                PathBuf::from("d"),
+                PathBuf::from(OsStr::from_bytes(b"\xff")),
+            ],
+            misplaced: vec![PathBuf::from("e")],
+            lower_bound: Some(2048),
+        };
+        let mut json = Vec::new();
+        plan.write(&mut json).unwrap();
+        assert_eq!(plan, serde_json::from_slice::<Plan>(&json).unwrap());
+    }
+
+    #[test]
+    fn merge_leaves_nothing_split() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 10)],
+            &[
+                ("a", "c", "x/1.txt", 10),
+                ("b", "c", "y/2.txt", 10),
+                ("b", "c", "y/3.txt", 10),
+            ],
+        );
+        state.roots.get_mut(&PathBuf::from("b")).unwrap().id = 0;
+        let plan = state.plan().unwrap();
+        assert!(plan.moves.contains(&Move {
+            source: PathBuf::from("b/c/y"),
+            target: PathBuf::from("a/c/y"),
+            ..Default::default()
+        }));
+        assert!(plan.split.is_empty());
+    }
+
+    #[test]
+    fn merge_leaves_unplaceable_in_place() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 10)],
+            &[
+                ("a", "c", "1.txt", 10),
+                ("b", "c", "2.txt", 10),
+                ("a", "d", "3.txt", 10),
+                ("b", "d", "4.txt", 10),
+                ("b", "d", "5.txt", 10),
+            ],
+        );
+        state.roots.get_mut(&PathBuf::from("b")).unwrap().id = 0;
+        Arc::make_mut(&mut state.scan).constraints =
+            Constraints::default().forbid("c", "a").forbid("c", "b");
+        let plan = state.plan().unwrap();
+        assert_eq!(
+            vec![
+                Move {
+                    source: PathBuf::from("a/d/3.txt"),
+                    target: PathBuf::from("b/d/3.txt"),
+                    ..Default::default()
+                },
+                Move {
+                    source: PathBuf::from("a/d"),
+                    target: PathBuf::from("b/d"),
+                    directory: true,
+                    ..Default::default()
+                },
+            ],
+            plan.moves
+        );
+        assert_eq!(vec![PathBuf::from("c")], plan.split);
+    }
+
+    #[test]
+    fn separate_filesystems_move_files() {
+        // As merge_leaves_nothing_split, but with each root on its own device
+        let state = fixture(
+            &[("a", 10), ("b", 10)],
+            &[
+                ("a", "c", "x/1.txt", 10),
+                ("b", "c", "y/2.txt", 10),
+                ("b", "c", "y/3.txt", 10),
+            ],
+        );
+        let plan = state.plan().unwrap();
+        assert_eq!(10, plan.cost);
+        assert_eq!(
+            vec![
+                Move {
+                    source: PathBuf::from("a/c/x/1.txt"),
+                    target: PathBuf::from("b/c/x/1.txt"),
+                    ..Default::default()
+                },
+                Move {
+                    source: PathBuf::from("a/c/x"),
+                    target: PathBuf::from("b/c/x"),
+                    directory: true,
+                    ..Default::default()
+                },
+                Move {
+                    source: PathBuf::from("a/c"),
+                    target: PathBuf::from("b/c"),
+                    directory: true,
+                    ..Default::default()
+                },
+            ],
+            plan.moves
+        );
+        assert!(plan.split.is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+};
+
+use crate::Plan;
+
+const PREAMBLE: &str = r#"set -eu
+
+absent() {
+    if [ -e "$1" ] || [ -h "$1" ]; then
+        echo "refusing to overwrite $1" >&2
+        exit 1
+    fi
+}
+
+same() {
+    if [ -h "$1" ]; then
+        [ "$(readlink -- "$1")" = "$(readlink -- "$2")" ]
+    else
+        cmp -- "$1" "$2"
+    fi
+}
+
+movedir() {
+    if [ ! -d "$2" ]; then
+        mkdir -- "$2"
+        chown -- "$3" "$2" 2>/dev/null || :
+        chmod -- "$4" "$2"
+        touch -r "$1" -- "$2"
+    fi
+    rmdir -- "$1"
+}
+"#;
+
+impl Plan {
+    /// Render the moves as a POSIX `sh` script, for hosts where the plan must be run by hand.
+    ///
+    /// Moves within a filesystem are an `mv`; moves between filesystems copy, compare and
+    /// only then remove the source, or link to the already moved copy of a hard-linked
+    /// file. Rewritten symbolic links are made afresh, and emptied directories made
+    /// with the owner and mode their source has as the script is written, before it is
+    /// removed. The script stops at the first failing command.
+    pub fn write_script(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "#!/bin/sh")?;
+        writeln!(
+            writer,
+            "# Relocation plan: {} moves, cost {}",
+            self.moves.len(),
+            self.cost
+        )?;
+        writeln!(writer, "{PREAMBLE}")?;
+        for (index, m) in self.moves.iter().enumerate() {
+            let same_filesystem = matches!(
+                (self.root_of(&m.source), self.root_of(&m.target)),
+                (Some(source), Some(target)) if source.filesystem.id == target.filesystem.id
+            );
+            writeln!(
+                writer,
+                "# {}/{}{}",
+                index + 1,
+                self.moves.len(),
+                if same_filesystem {
+                    ""
+                } else {
+                    " (cross-device)"
+                }
+            )?;
+            if !m.directory {
+                command(&mut writer, "absent", &[&m.target])?;
+            }
+            if let Some(parent) = m.target.parent() {
+                command(&mut writer, "mkdir -p --", &[parent])?;
+            }
+            if m.directory {
+                let metadata = fs::symlink_metadata(&m.source)?;
+                let owner = format!("{}:{}", metadata.uid(), metadata.gid());
+                let mode = format!("0{:o}", metadata.mode() & 0o7777);
+                command(
+                    &mut writer,
+                    "movedir",
+                    &[&m.source, &m.target, Path::new(&owner), Path::new(&mode)],
+                )?;
+            } else if let Some(symlink) = &m.symlink {
+                command(&mut writer, "ln -s --", &[symlink, &m.target])?;
+                command(&mut writer, "rm --", &[&m.source])?;
+            } else if same_filesystem {
+                command(&mut writer, "mv --", &[&m.source, &m.target])?;
+            } else if let Some(hard_link) = &m.hard_link {
+                command(&mut writer, "ln --", &[hard_link, &m.target])?;
+                command(&mut writer, "rm --", &[&m.source])?;
+            } else {
+                command(
+                    &mut writer,
+                    "cp -P --preserve=all --",
+                    &[&m.source, &m.target],
+                )?;
+                command(&mut writer, "same", &[&m.source, &m.target])?;
+                command(&mut writer, "rm --", &[&m.source])?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+}
+
+fn command(writer: &mut impl Write, command: &str, paths: &[&Path]) -> io::Result<()> {
+    writer.write_all(command.as_bytes())?;
+    for path in paths {
+        writer.write_all(b" ")?;
+        writer.write_all(&quote(path))?;
+    }
+    writer.write_all(b"\n")
+}
+
+/// Single-quote `path` for `sh`, byte for byte; only `'` itself needs escaping.
+fn quote(path: &Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for b in path.as_os_str().as_bytes() {
+        if *b == b'\'' {
+            quoted.extend_from_slice(br"'\''");
+        } else {
+            quoted.push(*b);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Path};
+
+    use super::quote;
+
+    #[test]
+    fn quotes_paths() {
+        assert_eq!(b"'/a b/c'".to_vec(), quote(Path::new("/a b/c")));
+        assert_eq!(
+            br"'/it'\''s/$HOME/`x`'".to_vec(),
+            quote(Path::new("/it's/$HOME/`x`"))
+        );
+        assert_eq!(
+            b"'/a/\xff\n.txt'".to_vec(),
+            quote(Path::new(OsStr::from_bytes(b"/a/\xff\n.txt")))
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{Move, State};
+
+/// Limits on a search, beyond which it settles for the best partial relocation found.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Budget {
+    /// Wall-clock time the search may take.
+    pub time: Option<Duration>,
+    /// Number of states the search may expand.
+    pub nodes: Option<u64>,
+}
+
+impl Budget {
+    pub fn time(mut self, time: Duration) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    pub fn nodes(mut self, nodes: u64) -> Self {
+        self.nodes = Some(nodes);
+        self
+    }
+}
+
+/// What is left of a budget, drawn on by one search or by several in turn.
+#[derive(Debug)]
+pub struct Allowance {
+    budget: Budget,
+    started: Instant,
+    nodes: u64,
+    exhausted: bool,
+}
+
+impl Allowance {
+    /// All of `budget`, its time counted from now.
+    pub fn new(budget: Budget) -> Self {
+        Self {
+            budget,
+            started: Instant::now(),
+            nodes: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Count the expansion of a state, returning whether the budget allows it.
+    pub(crate) fn spend(&mut self) -> bool {
+        if self.exhausted {
+            return false;
+        }
+        if self.budget.nodes.is_some_and(|nodes| self.nodes >= nodes)
+            || self
+                .budget
+                .time
+                .is_some_and(|time| self.started.elapsed() >= time)
+        {
+            warn!(
+                "Search budget exhausted after {} states in {:?}",
+                self.nodes,
+                self.started.elapsed()
+            );
+            self.exhausted = true;
+            return false;
+        }
+        self.nodes += 1;
+        true
+    }
+}
+
+/// Counts the states a search expands against its allowance, remembering that with
+/// the fewest subdirs unsettled.
+pub(crate) struct Tracker<'a> {
+    allowance: &'a mut Allowance,
+    best: Option<(usize, State)>,
+}
+
+impl<'a> Tracker<'a> {
+    pub(crate) fn new(allowance: &'a mut Allowance) -> Self {
+        Self {
+            allowance,
+            best: None,
+        }
+    }
+
+    /// Count the expansion of `state`, returning whether the allowance permits it.
+    ///
+    /// The state is remembered if it has the fewest subdirs unsettled so far, allowed or not.
+    pub(crate) fn expand(&mut self, state: &State) -> bool {
+        let unsettled = state.unsettled_count();
+        if !matches!(&self.best, Some((fewest, _)) if unsettled >= *fewest) {
+            self.best = Some((unsettled, state.clone()));
+        }
+        self.allowance.spend()
+    }
+
+    /// The successors of `state`, or none once the budget has run out.
+    pub(crate) fn successors(&mut self, state: &State) -> Box<dyn Iterator<Item = (State, u64)>> {
+        if self.expand(state) {
+            state.successors()
+        } else {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    /// If the budget ran out, the moves from `start` towards the state expanded with
+    /// the fewest subdirs unsettled, with their cost.
+    pub(crate) fn partial(self, start: &State) -> Option<(Vec<Move>, u64)> {
+        if !self.allowance.exhausted {
+            return None;
+        }
+        let (unsettled, best) = self.best?;
+        info!("Settling for a partial relocation, leaving {unsettled} subdirs unsettled");
+        Some(start.moves_to(&best))
+    }
+}
+
+impl State {
+    /// Number of subdirs not yet settled: split across roots, or on one they may not end on.
+    fn unsettled_count(&self) -> usize {
+        self.subdir_entries()
+            .iter()
+            .filter(|(subdir, indexes)| !self.settled(subdir, indexes))
+            .count()
+    }
+
+    /// Moves taking each entry straight to its root in `other`, ordered so that each
+    /// has room when made; any never having room are left out.
+    pub(crate) fn moves_to(&self, other: &State) -> (Vec<Move>, u64) {
+        let (moves, cost, left) = self.direct_moves(other);
+        if left != 0 {
+            warn!("{} files left in place, for want of room", left);
+        }
+        (moves, cost)
+    }
+
+    /// As [`State::moves_to`], also counting the files left in place.
+    pub(crate) fn direct_moves(&self, other: &State) -> (Vec<Move>, u64, usize) {
+        let targets = other
+            .entries
+            .iter()
+            .map(|entry| (entry.path(), &entry.root))
+            .collect::<HashMap<PathBuf, &PathBuf>>();
+        let mut pending = self
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                let target = targets.get(&entry.path())?;
+                (**target != entry.root).then_some((entry, *target))
+            })
+            .collect::<Vec<_>>();
+        let buckets = self.subdir_entries();
+        let mut roots = self.roots.clone();
+        let mut made = HashMap::<&PathBuf, HashSet<PathBuf>>::new();
+        let mut moves = Vec::new();
+        let mut cost = 0;
+        loop {
+            let before = pending.len();
+            pending.retain(|(entry, target)| {
+                let fs = &roots[*target];
+                let already = made.entry(*target).or_default();
+                let directories = self
+                    .scan
+                    .directories_made(
+                        [*entry],
+                        buckets[entry.subdir.as_path()]
+                            .iter()
+                            .map(|index| &self.entries[*index]),
+                        target,
+                        &self.usage,
+                    )
+                    .into_iter()
+                    .filter(|dir| !already.contains(dir))
+                    .collect::<Vec<_>>();
+                if !fs.has_room(entry.size) || !fs.has_inodes(1 + directories.len() as u64) {
+                    return true;
+                }
+                let count = directories.len() as u64;
+                already.extend(directories);
+                if let Some(fs) = roots.get_mut(&entry.root) {
+                    fs.blocks_available += fs.blocks(entry.size);
+                    fs.inodes_available += 1;
+                }
+                if let Some(fs) = roots.get_mut(*target) {
+                    fs.blocks_available -= fs.blocks(entry.size);
+                    fs.inodes_available = fs.inodes_available.saturating_sub(1 + count);
+                }
+                moves.extend(entry.moves(target, self.scan.symlinks));
+                cost += entry.size;
+                false
+            });
+            if pending.is_empty() || pending.len() == before {
+                break;
+            }
+        }
+        (moves, cost, pending.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::{Allowance, Budget, Tracker};
+    use crate::{state::fixture, IdaStar, State};
+
+    fn state(entries: &[(&str, &str, &str)]) -> State {
+        let entries = entries
+            .iter()
+            .map(|(root, subdir, subpath)| (*root, *subdir, *subpath, 10))
+            .collect::<Vec<_>>();
+        fixture(&[("a", 10), ("b", 10)], &entries)
+    }
+
+    #[test]
+    fn settles_for_fewest_split() {
+        let start = state(&[
+            ("a", "A", "1"),
+            ("b", "A", "2"),
+            ("a", "B", "3"),
+            ("b", "B", "4"),
+        ]);
+        let better = state(&[
+            ("a", "A", "1"),
+            ("a", "A", "2"),
+            ("a", "B", "3"),
+            ("b", "B", "4"),
+        ]);
+        let mut allowance = Allowance::new(Budget::default().nodes(2));
+        let mut tracker = Tracker::new(&mut allowance);
+        assert!(tracker.expand(&start));
+        assert!(tracker.expand(&better));
+        assert!(!tracker.expand(&start));
+        let (moves, cost) = tracker.partial(&start).unwrap();
+        assert_eq!(10, cost);
+        assert_eq!(1, moves.len());
+        assert_eq!(PathBuf::from("b/A/2"), moves[0].source);
+        assert_eq!(PathBuf::from("a/A/2"), moves[0].target);
+    }
+
+    #[test]
+    fn misplaced_is_unsettled() {
+        let state = |entries: &[(&str, &str, &str, u64)]| {
+            let mut state = fixture(&[("a", 10), ("b", 10), ("s", 10)], entries);
+            state.roots.get_mut(&PathBuf::from("s")).unwrap().scratch = true;
+            state
+        };
+        let start = state(&[
+            ("a", "A", "1", 10),
+            ("b", "A", "2", 10),
+            ("a", "B", "3", 10),
+            ("b", "B", "4", 10),
+        ]);
+        // Nothing split, but both subdirs staged on scratch
+        let staged = state(&[
+            ("s", "A", "1", 10),
+            ("s", "A", "2", 10),
+            ("s", "B", "3", 10),
+            ("s", "B", "4", 10),
+        ]);
+        let better = state(&[
+            ("a", "A", "1", 10),
+            ("a", "A", "2", 10),
+            ("a", "B", "3", 10),
+            ("b", "B", "4", 10),
+        ]);
+        let mut allowance = Allowance::new(Budget::default().nodes(3));
+        let mut tracker = Tracker::new(&mut allowance);
+        assert!(tracker.expand(&start));
+        assert!(tracker.expand(&staged));
+        assert!(tracker.expand(&better));
+        assert!(!tracker.expand(&start));
+        let (moves, cost) = tracker.partial(&start).unwrap();
+        assert_eq!(10, cost);
+        assert_eq!(PathBuf::from("a/A/2"), moves[0].target);
+
+        let out_of_time = IdaStar {
+            budget: Budget::default().time(Duration::ZERO),
+        };
+        let plan = staged.plan_with(&out_of_time).unwrap();
+        assert!(plan.split.is_empty());
+        assert_eq!(vec![PathBuf::from("A"), PathBuf::from("B")], plan.misplaced);
+    }
+
+    #[test]
+    fn complete_within_budget() {
+        let start = state(&[("a", "A", "1"), ("b", "A", "2")]);
+        let mut allowance = Allowance::new(Budget::default().nodes(2));
+        let mut tracker = Tracker::new(&mut allowance);
+        assert!(tracker.expand(&start));
+        assert_eq!(None, tracker.partial(&start));
+    }
+
+    #[test]
+    fn partial_plan() {
+        let start = state(&[
+            ("a", "A", "1"),
+            ("b", "A", "2"),
+            ("a", "B", "3"),
+            ("b", "B", "4"),
+        ]);
+        let out_of_time = IdaStar {
+            budget: Budget::default().time(Duration::ZERO),
+        };
+        let plan = start.plan_with(&out_of_time).unwrap();
+        assert!(plan.moves.is_empty());
+        assert_eq!(vec![PathBuf::from("A"), PathBuf::from("B")], plan.split);
+        assert!(plan.misplaced.is_empty());
+
+        let plan = start.plan_with(&IdaStar::default()).unwrap();
+        assert_eq!(2, plan.moves.iter().filter(|m| !m.directory).count());
+        assert!(plan.split.is_empty());
+    }
+}
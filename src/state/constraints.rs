@@ -0,0 +1,204 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::State;
+
+/// Where subdirs may, or may not, end up.
+///
+/// A pinned subdir must end on its root. A forbidden subdir must end on any
+/// root but the one forbidden. Nothing may be moved onto a root which is no
+/// target, though what is already there may stay.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Constraints {
+    pins: HashMap<PathBuf, PathBuf>,
+    forbidden: HashSet<(PathBuf, PathBuf)>,
+    no_target: HashSet<PathBuf>,
+}
+
+impl Constraints {
+    /// Keep `subdir` on `root`, gathering it there if need be.
+    pub fn pin(mut self, subdir: impl AsRef<Path>, root: impl AsRef<Path>) -> Self {
+        self.pins
+            .insert(subdir.as_ref().to_path_buf(), root.as_ref().to_path_buf());
+        self
+    }
+
+    /// Never leave `subdir` on `root`.
+    pub fn forbid(mut self, subdir: impl AsRef<Path>, root: impl AsRef<Path>) -> Self {
+        self.forbidden
+            .insert((subdir.as_ref().to_path_buf(), root.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Never move anything onto `root`.
+    pub fn no_target(mut self, root: impl AsRef<Path>) -> Self {
+        self.no_target.insert(root.as_ref().to_path_buf());
+        self
+    }
+
+    /// Read constraints from a file, one per line, as `pin SUBDIR=ROOT`,
+    /// `forbid SUBDIR=ROOT` or `no-target ROOT`.
+    ///
+    /// Blank lines, and those starting with `#`, are ignored.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut constraints = Self::default();
+        for (index, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let invalid = |reason: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{path:?} line {}: {reason}", index + 1),
+                )
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (kind, rest) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid("expected a constraint and its operand"))?;
+            let rest = rest.trim();
+            constraints = match kind {
+                "pin" | "forbid" => {
+                    let (subdir, root) = parse_placement(rest).map_err(|e| invalid(&e))?;
+                    if kind == "pin" {
+                        constraints.pin(subdir, root)
+                    } else {
+                        constraints.forbid(subdir, root)
+                    }
+                }
+                "no-target" => constraints.no_target(rest),
+                _ => return Err(invalid(&format!("unknown constraint {kind:?}"))),
+            };
+        }
+        Ok(constraints)
+    }
+
+    /// Whether `subdir` may end up on `root`.
+    pub(crate) fn may_hold(&self, subdir: &Path, root: &Path) -> bool {
+        match self.pins.get(subdir) {
+            Some(pinned) => pinned == root,
+            None => !self
+                .forbidden
+                .contains(&(subdir.to_path_buf(), root.to_path_buf())),
+        }
+    }
+
+    /// The root `subdir` is pinned to, if any.
+    pub(crate) fn pinned(&self, subdir: &Path) -> Option<&Path> {
+        self.pins.get(subdir).map(PathBuf::as_path)
+    }
+
+    /// Whether files of `subdir` may be moved onto `root`.
+    pub(crate) fn may_receive(&self, subdir: &Path, root: &Path) -> bool {
+        self.may_hold(subdir, root) && !self.no_target.contains(root)
+    }
+
+    fn roots(&self) -> impl Iterator<Item = &PathBuf> {
+        self.pins
+            .values()
+            .chain(self.forbidden.iter().map(|(_, root)| root))
+            .chain(&self.no_target)
+    }
+
+    fn subdirs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.pins
+            .keys()
+            .chain(self.forbidden.iter().map(|(subdir, _)| subdir))
+    }
+}
+
+/// Split `SUBDIR=ROOT`, at the last `=`.
+pub(crate) fn parse_placement(s: &str) -> Result<(PathBuf, PathBuf), String> {
+    let (subdir, root) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected SUBDIR=ROOT, not {s:?}"))?;
+    Ok((PathBuf::from(subdir), PathBuf::from(root)))
+}
+
+impl State {
+    /// Constrain where subdirs may end up; every root and subdir named must already
+    /// have been scanned.
+    pub fn set_constraints(&mut self, constraints: Constraints) -> io::Result<()> {
+        let mut resolved = HashMap::new();
+        for root in constraints.roots() {
+            resolved.insert(root.clone(), self.scanned_root(root)?);
+        }
+        for subdir in constraints.subdirs() {
+            if !self.usage.contains_key(&normalize(subdir)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{subdir:?} is not a scanned subdir"),
+                ));
+            }
+        }
+        let constraints = Constraints {
+            pins: constraints
+                .pins
+                .into_iter()
+                .map(|(subdir, root)| (normalize(&subdir), resolved[&root].clone()))
+                .collect(),
+            forbidden: constraints
+                .forbidden
+                .into_iter()
+                .map(|(subdir, root)| (normalize(&subdir), resolved[&root].clone()))
+                .collect(),
+            no_target: constraints
+                .no_target
+                .iter()
+                .map(|root| resolved[root].clone())
+                .collect(),
+        };
+        Arc::make_mut(&mut self.scan).constraints = constraints;
+        Ok(())
+    }
+}
+
+/// `subdir` as grouped, without any `.` components.
+fn normalize(subdir: &Path) -> PathBuf {
+    subdir
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use super::Constraints;
+
+    #[test]
+    fn load_constraints() {
+        let path = Path::new("test_constraints_load");
+        fs::write(
+            path,
+            "# NFS exports\npin Shows/X=/mnt/disk1\n\nforbid Films=/mnt/disk2\nno-target /mnt/disk3\n",
+        )
+        .unwrap();
+        let constraints = Constraints::load(path).unwrap();
+        assert_eq!(
+            Constraints::default()
+                .pin("Shows/X", "/mnt/disk1")
+                .forbid("Films", "/mnt/disk2")
+                .no_target("/mnt/disk3"),
+            constraints
+        );
+        let may_hold =
+            |subdir: &str, root: &str| constraints.may_hold(subdir.as_ref(), root.as_ref());
+        assert!(may_hold("Shows/X", "/mnt/disk1"));
+        assert!(!may_hold("Shows/X", "/mnt/disk2"));
+        assert!(!may_hold("Films", "/mnt/disk2"));
+        assert!(may_hold("Films", "/mnt/disk3"));
+        assert!(!constraints.may_receive("Films".as_ref(), "/mnt/disk3".as_ref()));
+
+        fs::write(path, "pin Shows/X\n").unwrap();
+        assert!(Constraints::load(path).is_err());
+        fs::write(path, "keep Shows/X=/mnt/disk1\n").unwrap();
+        assert!(Constraints::load(path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{
+    filesystem::FileSystem,
+    state::{budget::Allowance, Budget, Objective},
+    Move, Planner, State,
+};
+
+/// Relocates each subdir on its own, with `P`, searching jointly only for those
+/// which compete for the same space.
+///
+/// Subdirs pinned to the same root are relocated together, as they are bound to
+/// compete for its space. The relocations are made in turn, each only where every
+/// move still has room once those before it are made. Any subdirs which cannot be
+/// relocated alone without staging, or whose relocation never has room, are then
+/// relocated together.
+/// Objectives beyond consolidation concern every subdir at once, so are always
+/// searched for jointly. The searches share `P`'s budget, each drawing on what those
+/// before it left.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Decompose<P>(pub P);
+
+impl<P: Planner> Planner for Decompose<P> {
+    fn relocate_within(
+        &self,
+        state: &State,
+        allowance: &mut Allowance,
+    ) -> Option<(Vec<Move>, u64)> {
+        if state.objective != Objective::Consolidate {
+            return self.0.relocate_within(state, allowance);
+        }
+        let constraints = &state.scan.constraints;
+        let buckets = state.subdir_entries();
+        let mut groups = BTreeMap::<(Option<&Path>, &Path), Vec<&Path>>::new();
+        for subdir in buckets.keys() {
+            let key = match constraints.pinned(subdir) {
+                Some(root) => (Some(root), Path::new("")),
+                None => (None, *subdir),
+            };
+            groups.entry(key).or_default().push(subdir);
+        }
+        let mut pending = Vec::new();
+        let mut competing = Vec::new();
+        for subdirs in groups.into_values() {
+            let sub = state.subset(&state.roots, &buckets, &subdirs);
+            if sub.success() {
+                continue;
+            }
+            // A search may never end if there is no relocation to find; there is one
+            // at least where the subdirs fit straight onto roots they may end on
+            if !sub.consolidable() {
+                competing.extend(subdirs);
+                continue;
+            }
+            match self.0.relocate_within(&sub, allowance) {
+                Some(relocation) => pending.push((subdirs, sub, relocation)),
+                None => competing.extend(subdirs),
+            }
+        }
+
+        let mut roots = state.roots.clone();
+        let (mut moves, mut cost) = (Vec::new(), 0);
+        let mut separately = 0;
+        loop {
+            let before = pending.len();
+            pending.retain(|(_, sub, (sub_moves, sub_cost))| {
+                if !sub.admit(&mut roots, sub_moves) {
+                    return true;
+                }
+                moves.extend_from_slice(sub_moves);
+                cost += sub_cost;
+                separately += 1;
+                false
+            });
+            if pending.is_empty() || pending.len() == before {
+                break;
+            }
+        }
+        competing.extend(pending.into_iter().flat_map(|(subdirs, ..)| subdirs));
+        info!(
+            "{} groups of subdirs relocated separately, {} subdirs jointly",
+            separately,
+            competing.len()
+        );
+        if !competing.is_empty() {
+            let (joint_moves, joint_cost) = self
+                .0
+                .relocate_within(&state.subset(&roots, &buckets, &competing), allowance)?;
+            moves.extend(joint_moves);
+            cost += joint_cost;
+        }
+        Some((moves, cost))
+    }
+
+    fn budget(&self) -> Budget {
+        self.0.budget()
+    }
+}
+
+impl State {
+    /// This state, with only the entries of `subdirs`, found in `buckets` of entry
+    /// indexes by subdir, on `roots`.
+    fn subset(
+        &self,
+        roots: &HashMap<PathBuf, FileSystem>,
+        buckets: &BTreeMap<&Path, Vec<usize>>,
+        subdirs: &[&Path],
+    ) -> State {
+        let mut indexes = subdirs
+            .iter()
+            .flat_map(|subdir| &buckets[subdir])
+            .copied()
+            .collect::<Vec<_>>();
+        indexes.sort_unstable();
+        State {
+            roots: roots.clone(),
+            entries: indexes
+                .into_iter()
+                .map(|index| self.entries[index].clone())
+                .collect(),
+            usage: subdirs
+                .iter()
+                .filter_map(|subdir| self.usage.get_key_value(*subdir))
+                .map(|(subdir, usage)| (subdir.clone(), usage.clone()))
+                .collect(),
+            objective: self.objective.clone(),
+            scan: self.scan.clone(),
+        }
+    }
+
+    /// Make `moves` on `roots`, if each has room when made, returning whether they were.
+    fn admit(&self, roots: &mut HashMap<PathBuf, FileSystem>, moves: &[Move]) -> bool {
+        let mut locations = self
+            .entries
+            .iter()
+            .map(|entry| (entry.root.join(entry.path()), entry))
+            .collect::<HashMap<_, _>>();
+        let buckets = self.subdir_entries();
+        let mut trial = roots.clone();
+        let mut made = HashMap::<PathBuf, HashSet<PathBuf>>::new();
+        for m in moves.iter().filter(|m| m.hard_link.is_none()) {
+            let Some(entry) = locations.remove(&m.source) else {
+                continue;
+            };
+            let root_of = |location: &Path| {
+                trial
+                    .keys()
+                    .find(|root| root.join(entry.path()) == location)
+                    .cloned()
+            };
+            let (Some(source), Some(target)) = (root_of(&m.source), root_of(&m.target)) else {
+                return false;
+            };
+            let fs = &trial[&target];
+            let already = made.entry(target.clone()).or_default();
+            let directories = self
+                .scan
+                .directories_made(
+                    [entry],
+                    buckets[entry.subdir.as_path()]
+                        .iter()
+                        .map(|index| &self.entries[*index]),
+                    &target,
+                    &self.usage,
+                )
+                .into_iter()
+                .filter(|dir| !already.contains(dir))
+                .collect::<Vec<_>>();
+            if !fs.has_room(entry.size) || !fs.has_inodes(1 + directories.len() as u64) {
+                return false;
+            }
+            let count = directories.len() as u64;
+            already.extend(directories);
+            if let Some(fs) = trial.get_mut(&source) {
+                fs.blocks_available += fs.blocks(entry.size);
+                fs.inodes_available += 1;
+            }
+            if let Some(fs) = trial.get_mut(&target) {
+                fs.blocks_available -= fs.blocks(entry.size);
+                fs.inodes_available = fs.inodes_available.saturating_sub(1 + count);
+            }
+            locations.insert(m.target.clone(), entry);
+        }
+        *roots = trial;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::Decompose;
+    use crate::{
+        state::{fixture, Objective},
+        Budget, IdaStar, Planner,
+    };
+
+    #[test]
+    fn independent_subdirs() {
+        let state = fixture(
+            &[("a", 10), ("b", 10)],
+            &[
+                ("a", "A", "1", 10),
+                ("a", "A", "2", 20),
+                ("b", "A", "3", 40),
+                ("b", "B", "4", 5),
+                ("a", "B", "5", 6),
+                ("a", "C", "6", 7),
+            ],
+        );
+        let (moves, cost) = Decompose(IdaStar::default()).relocate(&state).unwrap();
+        assert_eq!(35, cost);
+        assert_eq!(3, moves.len());
+        assert_eq!(
+            Some(cost),
+            IdaStar::default().relocate(&state).map(|(_, cost)| cost)
+        );
+    }
+
+    #[test]
+    fn competing_subdirs() {
+        // Alone, each would gather onto a, which has room for only one of them
+        let state = fixture(
+            &[("a", 1), ("b", 10)],
+            &[
+                ("a", "A", "1", 20),
+                ("a", "A", "2", 20),
+                ("b", "A", "3", 10),
+                ("a", "B", "4", 20),
+                ("a", "B", "5", 20),
+                ("b", "B", "6", 10),
+            ],
+        );
+        let (moves, cost) = Decompose(IdaStar::default()).relocate(&state).unwrap();
+        assert_eq!(50, cost);
+        assert_eq!(3, moves.len());
+        assert!(moves.contains(&crate::Move {
+            source: PathBuf::from("b/A/3"),
+            target: PathBuf::from("a/A/3"),
+            ..Default::default()
+        }));
+        assert_eq!(
+            Some(cost),
+            IdaStar::default().relocate(&state).map(|(_, cost)| cost)
+        );
+    }
+
+    #[test]
+    fn shared_budget() {
+        let state = fixture(
+            &[("a", 10), ("b", 10)],
+            &[
+                ("a", "A", "1", 10),
+                ("b", "A", "2", 20),
+                ("a", "B", "3", 10),
+                ("b", "B", "4", 20),
+            ],
+        );
+        // Enough for either subdir alone, but not for both
+        let planner = Decompose(IdaStar {
+            budget: Budget::default().nodes(1),
+        });
+        let plan = state.plan_with(&planner).unwrap();
+        assert_eq!(10, plan.cost);
+        assert_eq!(vec![PathBuf::from("B")], plan.split);
+    }
+
+    #[test]
+    fn rebalance_is_joint() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 90)],
+            &[("a", "A", "1", 39 * 4096), ("a", "B", "2", 10)],
+        );
+        state.set_objective(Objective::Rebalance { tolerance: 1000 });
+        let (_, cost) = Decompose(IdaStar::default()).relocate(&state).unwrap();
+        assert_eq!(39 * 4096, cost);
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{plan::innermost_root, Move, State};
+
+impl State {
+    /// Moves of the directories which `moves` leave empty, to follow their contents.
+    ///
+    /// Each empty directory goes wherever the rest of its group ends up. Unless
+    /// source directories are kept, so too does every directory the moves empty,
+    /// to the root its contents went to. Directories come after the moves which
+    /// empty them, and before their parents.
+    pub(crate) fn directory_moves(&self, moves: &[Move]) -> Vec<Move> {
+        // Root each path moved away went to, keyed by (root, path within root)
+        let mut moved = HashMap::new();
+        for m in moves {
+            let root = match self.root_of(&m.source) {
+                Some(root) => root,
+                None => continue,
+            };
+            let relative = m.source.strip_prefix(root).unwrap_or(&m.source);
+            if let Some(target_root) = m.target.ancestors().nth(relative.components().count()) {
+                moved.insert((root, relative), target_root);
+            }
+        }
+        // Where a path ends up: moved itself, along with an ancestor, or not at all
+        let destination = |root: &PathBuf, path: &Path| {
+            path.ancestors()
+                .find_map(|p| moved.get(&(root, p)).copied())
+        };
+
+        // Roots each group ends up on
+        let mut homes = HashMap::<&PathBuf, HashSet<&Path>>::new();
+        for entry in &self.entries {
+            let home = destination(&entry.root, &entry.path()).unwrap_or(&entry.root);
+            homes.entry(&entry.subdir).or_default().insert(home);
+        }
+        let mut markers = HashMap::new();
+        let mut result = Vec::new();
+        let mut staying = Vec::new();
+        for (root, path) in &self.scan.empty_dirs {
+            if destination(root, path).is_some() {
+                continue;
+            }
+            let (subdir, _) = self
+                .scan
+                .grouping
+                .split(self.roots.keys(), path, true, &mut markers);
+            let home = homes
+                .get(&subdir)
+                .filter(|homes| homes.len() == 1)
+                .and_then(|homes| homes.iter().next());
+            match home {
+                Some(home) if *home != root.as_path() => result.push((root, path.clone(), *home)),
+                _ => staying.push((root, path.clone())),
+            }
+        }
+
+        if !self.scan.keep_source_dirs {
+            // Every directory which still holds something once the moves are made
+            let held = self
+                .entries
+                .iter()
+                .flat_map(|e| e.paths().map(move |path| (&e.root, path)))
+                .filter(|(root, path)| destination(root, path).is_none())
+                .chain(
+                    self.scan
+                        .excluded
+                        .iter()
+                        .map(|(root, path)| (root, path.clone())),
+                )
+                .chain(staying)
+                .flat_map(|(root, path)| {
+                    path.ancestors()
+                        .map(|p| (root, p.to_path_buf()))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<HashSet<_>>();
+            // Directories above everything moved away, with where (the first of) their contents went
+            let leaving = moves
+                .iter()
+                .filter_map(|m| {
+                    let root = self.root_of(&m.source)?;
+                    let relative = m.source.strip_prefix(root).ok()?;
+                    Some((root, relative.to_path_buf(), *moved.get(&(root, relative))?))
+                })
+                .chain(result.iter().cloned())
+                .collect::<Vec<_>>();
+            let mut emptied = HashSet::new();
+            for (root, path, target_root) in leaving {
+                for dir in path.ancestors().skip(1) {
+                    let dir = (root, dir.to_path_buf());
+                    if dir.1.as_os_str().is_empty()
+                        || held.contains(&dir)
+                        || destination(root, &dir.1).is_some()
+                        || !emptied.insert(dir.clone())
+                    {
+                        continue;
+                    }
+                    result.push((root, dir.1, target_root));
+                }
+            }
+        }
+
+        // Deepest first, so each directory is empty by the time it is moved
+        result.sort_by(|(a_root, a, _), (b_root, b, _)| {
+            b.components()
+                .count()
+                .cmp(&a.components().count())
+                .then_with(|| (a_root, a).cmp(&(b_root, b)))
+        });
+        result
+            .into_iter()
+            .map(|(root, path, target_root)| Move {
+                source: root.join(&path),
+                target: target_root.join(&path),
+                hard_link: None,
+                symlink: None,
+                directory: true,
+            })
+            .collect()
+    }
+
+    /// The (innermost) root containing `path`.
+    fn root_of(&self, path: &Path) -> Option<&PathBuf> {
+        innermost_root(self.roots.keys(), path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{filesystem::FileSystem, state::Grouping, Entry, Move, State};
+
+    #[test]
+    fn emptied_directories_follow_their_contents() {
+        let mut state = State {
+            roots: [("a", 0), ("b", 1)]
+                .iter()
+                .map(|(root, id)| (PathBuf::from(root), FileSystem::new(*id, 4096, 10, false)))
+                .collect(),
+            ..Default::default()
+        };
+        state.set_grouping(Grouping::Depth(2));
+        for (root, subdir, subpath) in [
+            ("a", "Shows/X", "1"),
+            ("b", "Shows/X", "2"),
+            ("a", "Shows/Y", "3"),
+        ] {
+            state.add_entry(Entry {
+                root: PathBuf::from(root),
+                subdir: PathBuf::from(subdir),
+                subpath: PathBuf::from(subpath),
+                ..Default::default()
+            });
+        }
+        Arc::make_mut(&mut state.scan)
+            .empty_dirs
+            .insert((PathBuf::from("a"), PathBuf::from("Shows/X/extras")));
+        let moves = vec![Move {
+            source: PathBuf::from("a/Shows/X/1"),
+            target: PathBuf::from("b/Shows/X/1"),
+            ..Default::default()
+        }];
+        let directory = |path: &str| Move {
+            source: PathBuf::from("a").join(path),
+            target: PathBuf::from("b").join(path),
+            directory: true,
+            ..Default::default()
+        };
+        // Shows still holds Y
+        assert_eq!(
+            vec![directory("Shows/X/extras"), directory("Shows/X")],
+            state.directory_moves(&moves)
+        );
+        state.set_keep_source_dirs(true);
+        assert_eq!(
+            vec![directory("Shows/X/extras")],
+            state.directory_moves(&moves)
+        );
+    }
+}
@@ -0,0 +1,475 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use log::{error, info};
+
+use crate::{
+    filesystem::FileSystem,
+    state::{budget::Allowance, Budget, Objective},
+    Entry, Move, Planner, State,
+};
+
+/// Assigns each subdir wholly to one root, by branch and bound, for the fewest bytes
+/// moved that leave every root within its capacity.
+///
+/// Unless its budget runs out first, or a cheaper assignment is left for want of an
+/// order where each move has room, the relocation found is the cheapest there is of
+/// whole subdirs straight onto their roots; else the best found, with a bound on how
+/// far from the cheapest it may be.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BranchAndBound {
+    pub budget: Budget,
+}
+
+/// A relocation, with a lower bound on the cost of any other.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Bounded {
+    pub moves: Vec<Move>,
+    pub cost: u64,
+    pub lower_bound: u64,
+}
+
+impl Bounded {
+    /// How many bytes more the relocation may move than the cheapest; 0 if it is the cheapest.
+    pub fn gap(&self) -> u64 {
+        self.cost.saturating_sub(self.lower_bound)
+    }
+}
+
+impl Planner for BranchAndBound {
+    fn relocate_within(
+        &self,
+        state: &State,
+        allowance: &mut Allowance,
+    ) -> Option<(Vec<Move>, u64)> {
+        let bounded = self.solve_within(state, allowance)?;
+        Some((bounded.moves, bounded.cost))
+    }
+
+    fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    fn relocate_bounded(&self, state: &State) -> Option<(Vec<Move>, u64, Option<u64>)> {
+        let bounded = self.solve(state)?;
+        Some((bounded.moves, bounded.cost, Some(bounded.lower_bound)))
+    }
+}
+
+impl BranchAndBound {
+    /// The cheapest relocation of `state`'s subdirs found within the budget, with a
+    /// lower bound on the cost of any; `None` if none was found.
+    pub fn solve(&self, state: &State) -> Option<Bounded> {
+        self.solve_within(state, &mut Allowance::new(self.budget))
+    }
+
+    /// As [`BranchAndBound::solve`], drawing on `allowance`.
+    fn solve_within(&self, state: &State, allowance: &mut Allowance) -> Option<Bounded> {
+        let mut search = Search::new(state, allowance);
+        search.branch(0, 0);
+        let Some((cost, moves)) = search.best.take() else {
+            error!("No relocation of whole subdirs found");
+            return None;
+        };
+        let bounded = Bounded {
+            moves,
+            cost,
+            lower_bound: search.open_bound.min(cost),
+        };
+        info!(
+            "Relocation found, with {} moves, costing {}, at most {} more than the cheapest",
+            bounded.moves.len(),
+            bounded.cost,
+            bounded.gap()
+        );
+        Some(bounded)
+    }
+}
+
+/// A subdir, with the cost of ending on each root it may end on, cheapest first.
+struct Group<'a> {
+    subdir: &'a Path,
+    options: Vec<(u64, usize)>,
+    /// Blocks and inodes held on each root.
+    held: Vec<(i64, i64)>,
+    /// Blocks and inodes, directories made included, needed to gather onto each root.
+    gathered: Vec<(i64, i64)>,
+}
+
+struct Search<'a> {
+    state: &'a State,
+    roots: Vec<(&'a PathBuf, &'a FileSystem)>,
+    groups: Vec<Group<'a>>,
+    /// Least cost of the groups from each onwards, whatever their capacity.
+    least: Vec<u64>,
+    /// Blocks and inodes free on each root, beyond any reserve, as assigned so far.
+    free: Vec<(i64, i64)>,
+    /// Blocks and inodes the groups yet to be assigned hold on each root.
+    releasable: Vec<(i64, i64)>,
+    assignment: Vec<usize>,
+    best: Option<(u64, Vec<Move>)>,
+    /// Least lower bound of the branches left unexplored for want of budget, or
+    /// of the assignments with no order found for their moves.
+    open_bound: u64,
+    allowance: &'a mut Allowance,
+}
+
+impl<'a> Search<'a> {
+    fn new(state: &'a State, allowance: &'a mut Allowance) -> Self {
+        let mut roots = state.roots.iter().collect::<Vec<_>>();
+        roots.sort_by_key(|(root, _)| *root);
+        let index = roots
+            .iter()
+            .enumerate()
+            .map(|(index, (root, _))| (root.as_path(), index))
+            .collect::<HashMap<_, _>>();
+        let mut subdirs = BTreeMap::<&Path, Vec<&Entry>>::new();
+        for entry in &state.entries {
+            subdirs.entry(&entry.subdir).or_default().push(entry);
+        }
+        let mut groups = subdirs
+            .into_iter()
+            .map(|(subdir, entries)| {
+                let mut held = vec![(0, 0); roots.len()];
+                let mut bytes = vec![0; roots.len()];
+                for entry in &entries {
+                    let root = index[entry.root.as_path()];
+                    held[root].0 += roots[root].1.blocks(entry.size) as i64;
+                    held[root].1 += 1;
+                    bytes[root] += entry.size;
+                }
+                let total = bytes.iter().sum::<u64>();
+                let gathered = roots
+                    .iter()
+                    .enumerate()
+                    .map(|(root, (path, fs))| {
+                        let moving = entries
+                            .iter()
+                            .filter(|entry| index[entry.root.as_path()] != root)
+                            .copied();
+                        let directories = state.scan.directories_made(
+                            moving.clone(),
+                            entries.iter().copied(),
+                            path,
+                            &state.usage,
+                        );
+                        moving.fold((0, directories.len() as i64), |(blocks, inodes), entry| {
+                            (blocks + fs.blocks(entry.size) as i64, inodes + 1)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                let constraints = &state.scan.constraints;
+                let mut options = roots
+                    .iter()
+                    .enumerate()
+                    .filter(|(root, (path, fs))| {
+                        fs.keeps_files()
+                            && (constraints.may_receive(subdir, path)
+                                || constraints.may_hold(subdir, path) && bytes[*root] == total)
+                    })
+                    .map(|(root, _)| (total - bytes[root], root))
+                    .collect::<Vec<_>>();
+                options.sort();
+                Group {
+                    subdir,
+                    options,
+                    held,
+                    gathered,
+                }
+            })
+            .collect::<Vec<_>>();
+        // Largest first, as they constrain the rest the most
+        groups.sort_by_key(|group| {
+            std::cmp::Reverse(group.held.iter().map(|(blocks, _)| blocks).sum::<i64>())
+        });
+        let mut least = vec![0u64; groups.len() + 1];
+        for (index, group) in groups.iter().enumerate().rev() {
+            let cheapest = group.options.first().map_or(u64::MAX, |(cost, _)| *cost);
+            least[index] = least[index + 1].saturating_add(cheapest);
+        }
+        let free = roots
+            .iter()
+            .map(|(_, fs)| {
+                let blocks = fs.blocks_available as i64 - fs.reserved_blocks as i64;
+                // One inode is always left to spare
+                let inodes = if fs.inodes_total == 0 {
+                    i64::MAX / 2
+                } else {
+                    fs.inodes_available as i64 - 1
+                };
+                (blocks, inodes)
+            })
+            .collect::<Vec<_>>();
+        let mut releasable = vec![(0, 0); roots.len()];
+        for group in &groups {
+            for (root, (blocks, inodes)) in group.held.iter().enumerate() {
+                releasable[root].0 += blocks;
+                releasable[root].1 += inodes;
+            }
+        }
+        Self {
+            state,
+            roots,
+            groups,
+            least,
+            free,
+            releasable,
+            assignment: Vec::new(),
+            best: None,
+            open_bound: u64::MAX,
+            allowance,
+        }
+    }
+
+    /// Assign the groups from `index` onwards, the cost of those before being `cost`.
+    fn branch(&mut self, index: usize, cost: u64) {
+        let bound = cost.saturating_add(self.least[index]);
+        if self.best.as_ref().is_some_and(|(best, _)| bound >= *best) {
+            return;
+        }
+        if !self.allowance.spend() {
+            self.open_bound = self.open_bound.min(bound);
+            return;
+        }
+        if index == self.groups.len() {
+            if !self.objective_met() {
+                return;
+            }
+            match self.ordered() {
+                Some(moves) => self.best = Some((cost, moves)),
+                // No order was found, not that none exists, so this may yet be the cheapest
+                None => self.open_bound = self.open_bound.min(cost),
+            }
+            return;
+        }
+        for option in 0..self.groups[index].options.len() {
+            let (step, root) = self.groups[index].options[option];
+            self.assign(index, root, 1);
+            if self.fits() {
+                self.assignment.push(root);
+                self.branch(index + 1, cost + step);
+                self.assignment.pop();
+            }
+            self.assign(index, root, -1);
+        }
+    }
+
+    /// Gather group `index` onto `root`, or with a `sign` of -1, undo so doing.
+    fn assign(&mut self, index: usize, root: usize, sign: i64) {
+        let group = &self.groups[index];
+        for (other, (blocks, inodes)) in group.held.iter().enumerate() {
+            self.releasable[other].0 -= sign * blocks;
+            self.releasable[other].1 -= sign * inodes;
+            if other != root {
+                self.free[other].0 += sign * blocks;
+                self.free[other].1 += sign * inodes;
+            }
+        }
+        self.free[root].0 -= sign * group.gathered[root].0;
+        self.free[root].1 -= sign * group.gathered[root].1;
+    }
+
+    /// Whether each root can end within its capacity, were the groups yet to be
+    /// assigned to release all they hold on it.
+    fn fits(&self) -> bool {
+        self.free
+            .iter()
+            .zip(&self.releasable)
+            .all(|(free, releasable)| free.0 + releasable.0 >= 0 && free.1 + releasable.1 >= 0)
+    }
+
+    /// The moves gathering the groups as assigned, if they can be made in an order where
+    /// each has room.
+    fn ordered(&self) -> Option<Vec<Move>> {
+        let targets = self
+            .groups
+            .iter()
+            .zip(&self.assignment)
+            .map(|(group, root)| (group.subdir, self.roots[*root].0))
+            .collect::<HashMap<_, _>>();
+        let mut target = self.state.clone();
+        for entry in &mut target.entries {
+            entry.root = targets[entry.subdir.as_path()].clone();
+        }
+        let (moves, _, left) = self.state.direct_moves(&target);
+        (left == 0).then_some(moves)
+    }
+
+    /// Whether the objective, beyond consolidation, is met once the groups are gathered as assigned.
+    fn objective_met(&self) -> bool {
+        if self.state.objective == Objective::Consolidate {
+            return true;
+        }
+        let roots = self
+            .roots
+            .iter()
+            .zip(&self.free)
+            .map(|((path, fs), (blocks, _))| {
+                let mut fs = (*fs).clone();
+                fs.blocks_available = (blocks + fs.reserved_blocks as i64).max(0) as u64;
+                ((*path).clone(), fs)
+            })
+            .collect();
+        State {
+            roots,
+            objective: self.state.objective.clone(),
+            ..Default::default()
+        }
+        .objective_met()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::BranchAndBound;
+    use crate::{
+        state::{fixture, Objective},
+        Budget, Constraints, IdaStar, Planner,
+    };
+
+    #[test]
+    fn matches_search() {
+        let state = fixture(
+            &[("a", 10), ("b", 10)],
+            &[
+                ("a", "A", "1", 10),
+                ("a", "A", "2", 20),
+                ("b", "A", "3", 40),
+                ("b", "B", "4", 5),
+                ("a", "B", "5", 6),
+            ],
+        );
+        let bounded = BranchAndBound::default().solve(&state).unwrap();
+        assert_eq!(35, bounded.cost);
+        assert_eq!(0, bounded.gap());
+        assert_eq!(3, bounded.moves.len());
+        assert_eq!(
+            Some(bounded.cost),
+            IdaStar::default().relocate(&state).map(|(_, cost)| cost)
+        );
+    }
+
+    #[test]
+    fn beats_greedy() {
+        // Largest first, A takes a's only free block, which B would better use
+        let state = fixture(
+            &[("a", 1), ("b", 10)],
+            &[
+                ("a", "A", "1", 100),
+                ("b", "A", "2", 90),
+                ("a", "B", "3", 60),
+                ("b", "B", "4", 10),
+            ],
+        );
+        assert_eq!(Some(150), state.greedy().map(|(_, cost)| cost));
+        let bounded = BranchAndBound::default().solve(&state).unwrap();
+        assert_eq!(110, bounded.cost);
+        assert_eq!(0, bounded.gap());
+        // a must be made room on before B moves onto it
+        assert_eq!(PathBuf::from("a/A/1"), bounded.moves[0].source);
+        assert_eq!(PathBuf::from("b/B/4"), bounded.moves[1].source);
+    }
+
+    #[test]
+    fn constrained() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 10)],
+            &[("a", "A", "1", 10), ("b", "A", "2", 20)],
+        );
+        std::sync::Arc::make_mut(&mut state.scan).constraints =
+            Constraints::default().pin("A", "b");
+        assert_eq!(10, BranchAndBound::default().solve(&state).unwrap().cost);
+        std::sync::Arc::make_mut(&mut state.scan).constraints =
+            Constraints::default().forbid("A", "b");
+        assert_eq!(20, BranchAndBound::default().solve(&state).unwrap().cost);
+    }
+
+    #[test]
+    fn swap_without_room() {
+        // Each subdir would best gather onto the other's root, freeing just the room
+        // the other needs, but neither root has room for the first move
+        let state = fixture(
+            &[("a", 0), ("b", 0)],
+            &[
+                ("a", "A", "1", 10),
+                ("b", "A", "2", 5 * 4096),
+                ("b", "B", "3", 10),
+                ("a", "B", "4", 5 * 4096),
+            ],
+        );
+        assert_eq!(None, BranchAndBound::default().solve(&state));
+    }
+
+    #[test]
+    fn no_room() {
+        let state = fixture(
+            &[("a", 0), ("b", 0)],
+            &[("a", "A", "1", 10), ("b", "A", "2", 10)],
+        );
+        assert_eq!(None, BranchAndBound::default().solve(&state));
+    }
+
+    #[test]
+    fn bounded_gap() {
+        let state = fixture(
+            &[("a", 1), ("b", 10)],
+            &[
+                ("a", "A", "1", 100),
+                ("b", "A", "2", 90),
+                ("a", "B", "3", 60),
+                ("b", "B", "4", 10),
+            ],
+        );
+        // Out of budget at the first assignment found, A on a and B on b, with A on b unexplored
+        let planner = BranchAndBound {
+            budget: Budget::default().nodes(3),
+        };
+        let bounded = planner.solve(&state).unwrap();
+        assert_eq!(150, bounded.cost);
+        assert_eq!(110, bounded.lower_bound);
+        assert_eq!(40, bounded.gap());
+
+        let plan = state.plan_with(&planner).unwrap();
+        assert_eq!(150, plan.cost);
+        assert_eq!(Some(110), plan.lower_bound);
+        assert_eq!(
+            None,
+            state.plan_with(&IdaStar::default()).unwrap().lower_bound
+        );
+    }
+
+    #[test]
+    fn unordered_leaves_gap() {
+        // Swapping X onto a and Y onto b is cheapest, but with both full neither move
+        // goes first, so X goes onto c instead
+        let state = fixture(
+            &[("a", 0), ("b", 0), ("c", 10)],
+            &[
+                ("a", "X", "1", 2 * 4096),
+                ("b", "X", "2", 4096),
+                ("b", "Y", "3", 2 * 4096),
+                ("a", "Y", "4", 4096),
+            ],
+        );
+        let bounded = BranchAndBound::default().solve(&state).unwrap();
+        assert_eq!(4 * 4096, bounded.cost);
+        assert_eq!(2 * 4096, bounded.lower_bound);
+        assert_eq!(2 * 4096, bounded.gap());
+    }
+
+    #[test]
+    fn rebalance() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 90)],
+            &[("a", "A", "1", 39 * 4096), ("a", "B", "2", 10)],
+        );
+        state.set_objective(Objective::Rebalance { tolerance: 1000 });
+        let bounded = BranchAndBound::default().solve(&state).unwrap();
+        assert_eq!(39 * 4096, bounded.cost);
+        assert_eq!(0, bounded.gap());
+    }
+}
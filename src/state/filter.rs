@@ -0,0 +1,86 @@
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// Name of the per-root file of patterns to exclude, by default.
+pub const DEFAULT_IGNORE_FILE: &str = ".relocationignore";
+
+/// Gitignore-style patterns choosing which paths within each root are scanned.
+///
+/// Excluded paths are neither entries nor moved, though they still take up space
+/// on their root. Patterns apply in turn, later ones overriding earlier: those of
+/// the ignore file, then exclusions, then inclusions. The ignore file itself is
+/// always excluded.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Filter {
+    exclude: Vec<String>,
+    include: Vec<String>,
+    ignore_file: Option<PathBuf>,
+}
+
+impl Filter {
+    /// Exclude paths matching `pattern`.
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(pattern.to_string());
+        self
+    }
+
+    /// Scan paths matching `pattern`, even if otherwise excluded.
+    ///
+    /// As with gitignore, nothing within an excluded directory can be included.
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(format!("!{pattern}"));
+        self
+    }
+
+    /// Also read patterns from a file of this name at the top of each root, if present.
+    pub fn ignore_file(mut self, name: impl AsRef<Path>) -> Self {
+        self.ignore_file = Some(name.as_ref().to_path_buf());
+        self
+    }
+
+    /// The patterns as they apply within `root`.
+    pub(crate) fn matcher(&self, root: &Path) -> Result<Gitignore, ignore::Error> {
+        let mut builder = GitignoreBuilder::new(root);
+        if let Some(name) = &self.ignore_file {
+            let path = root.join(name);
+            if path.is_file() {
+                if let Some(error) = builder.add(path) {
+                    return Err(error);
+                }
+            }
+        }
+        for line in self.exclude.iter().chain(&self.include) {
+            builder.add_line(None, line)?;
+        }
+        if let Some(name) = &self.ignore_file {
+            // Each root keeps its own ignore file
+            builder.add_line(None, &format!("/{}", name.display()))?;
+        }
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::Filter;
+
+    #[test]
+    fn later_patterns_override() {
+        let filter = Filter::default()
+            .exclude("*.part")
+            .exclude("/lost+found")
+            .exclude(".Trash-*")
+            .include("keep.part");
+        let matcher = filter.matcher(Path::new("/r")).unwrap();
+        let excluded = |path: &str, is_dir| matcher.matched(path, is_dir).is_ignore();
+        assert!(excluded("/r/a/b.part", false));
+        assert!(!excluded("/r/a/keep.part", false));
+        assert!(excluded("/r/lost+found", true));
+        assert!(!excluded("/r/a/lost+found", true));
+        assert!(excluded("/r/a/.Trash-1000", true));
+        assert!(!excluded("/r/a/b.txt", false));
+    }
+}
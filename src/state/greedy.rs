@@ -0,0 +1,442 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, error, info, warn};
+
+use crate::{state::Objective, Move, State};
+
+impl State {
+    /// Relocate whole subdirs at a time, first-fit decreasing.
+    ///
+    /// Subdirs which are split, or on a root they may not end on, are placed largest
+    /// first, each on the allowed root with room needing the fewest bytes moved: that
+    /// already holding most of it, where it fits. Subdirs which fit nowhere are retried
+    /// once others have moved out of their way. To rebalance, whole subdirs then move
+    /// from the fullest root to the emptiest, while that narrows the gap between them.
+    pub(crate) fn greedy(&self) -> Option<(Vec<Move>, u64)> {
+        let groups = self.subdir_entries();
+        let (mut state, mut moves, mut cost) = match self.first_fit(&groups) {
+            Ok(placed) => placed,
+            Err(unplaced) => {
+                error!("No complete relocation found: no room for {:?}", unplaced);
+                return None;
+            }
+        };
+        cost += state.greedy_rebalance(&groups, &mut moves);
+        info!(
+            "Greedy relocation found, with {} moves, costing {}",
+            moves.len(),
+            cost
+        );
+        Some((moves, cost))
+    }
+
+    /// Whether every subdir can be gathered onto a root where it may end, without staging.
+    pub(crate) fn consolidable(&self) -> bool {
+        self.first_fit(&self.subdir_entries()).is_ok()
+    }
+
+    /// Indexes of the entries of each subdir.
+    pub(crate) fn subdir_entries(&self) -> BTreeMap<&Path, Vec<usize>> {
+        let mut groups = BTreeMap::<&Path, Vec<usize>>::new();
+        for (index, entry) in self.entries.iter().enumerate() {
+            groups.entry(&entry.subdir).or_default().push(index);
+        }
+        groups
+    }
+
+    /// Place the subdirs not yet settled, largest first, returning the resulting state,
+    /// moves and their cost; or the subdirs fitting nowhere.
+    fn first_fit<'a>(
+        &self,
+        groups: &'a BTreeMap<&Path, Vec<usize>>,
+    ) -> Result<(State, Vec<Move>, u64), Vec<&'a Path>> {
+        let mut pending = groups
+            .iter()
+            .filter(|(subdir, indexes)| !self.settled(subdir, indexes))
+            .map(|(subdir, indexes)| (*subdir, indexes.as_slice()))
+            .collect::<Vec<_>>();
+        pending.sort_by_key(|(subdir, indexes)| (Reverse(self.bytes(indexes)), *subdir));
+        debug!("{} of {} subdirs to place", pending.len(), groups.len());
+
+        let mut state = self.clone();
+        let mut moves = Vec::new();
+        let mut cost = 0;
+        loop {
+            let before = pending.len();
+            pending.retain(
+                |(subdir, indexes)| match state.greedy_target(subdir, indexes) {
+                    Some(target) => {
+                        cost += state.shift(indexes, &target, &mut moves);
+                        false
+                    }
+                    None => true,
+                },
+            );
+            if pending.is_empty() || pending.len() == before {
+                break;
+            }
+        }
+        if !pending.is_empty() {
+            return Err(pending.into_iter().map(|(subdir, _)| subdir).collect());
+        }
+        Ok((state, moves, cost))
+    }
+
+    fn bytes(&self, indexes: &[usize]) -> u64 {
+        indexes.iter().map(|index| self.entries[*index].size).sum()
+    }
+
+    /// Whether the subdir is wholly on one root, where it may end.
+    pub(crate) fn settled(&self, subdir: &Path, indexes: &[usize]) -> bool {
+        let root = &self.entries[indexes[0]].root;
+        indexes
+            .iter()
+            .all(|index| self.entries[*index].root == *root)
+            && self.roots[root].keeps_files()
+            && self.scan.constraints.may_hold(subdir, root)
+    }
+
+    /// The allowed root with room for the subdir, needing the fewest bytes moved.
+    fn greedy_target(&self, subdir: &Path, indexes: &[usize]) -> Option<PathBuf> {
+        let mut held = HashMap::<&Path, u64>::new();
+        for index in indexes {
+            let entry = &self.entries[*index];
+            *held.entry(&entry.root).or_default() += entry.size;
+        }
+        let total = self.bytes(indexes);
+        let mut candidates = self
+            .roots
+            .iter()
+            .filter(|(root, fs)| {
+                fs.keeps_files() && self.scan.constraints.may_receive(subdir, root)
+            })
+            .map(|(root, fs)| {
+                let moved = total - held.get(root.as_path()).copied().unwrap_or_default();
+                (moved, Reverse(fs.usable_bytes()), root)
+            })
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates
+            .into_iter()
+            .map(|(.., root)| root)
+            .find(|root| self.fits(indexes, root))
+            .cloned()
+    }
+
+    /// Whether `target` has the blocks and inodes for those of the entries not already
+    /// on it, and the directories made for them.
+    fn fits(&self, indexes: &[usize], target: &Path) -> bool {
+        let fs = &self.roots[target];
+        let (blocks, inodes) = indexes
+            .iter()
+            .map(|index| &self.entries[*index])
+            .filter(|entry| entry.root != target)
+            .fold((0, 0), |(blocks, inodes), entry| {
+                (blocks + fs.blocks(entry.size), inodes + 1)
+            });
+        fs.has_blocks(blocks) && fs.has_inodes(inodes + self.directories_made(indexes, target))
+    }
+
+    /// Number of directories moving the entries not already on `target` there makes.
+    fn directories_made(&self, indexes: &[usize], target: &Path) -> u64 {
+        let entries = indexes.iter().map(|index| &self.entries[*index]);
+        self.scan
+            .directories_made(
+                entries.clone().filter(|entry| entry.root != target),
+                entries,
+                target,
+                &self.usage,
+            )
+            .len() as u64
+    }
+
+    /// Move the entries not already on `target` onto it, returning the bytes moved.
+    fn shift(&mut self, indexes: &[usize], target: &Path, moves: &mut Vec<Move>) -> u64 {
+        let directories = self.directories_made(indexes, target);
+        if let Some(fs) = self.roots.get_mut(target) {
+            fs.inodes_available = fs.inodes_available.saturating_sub(directories);
+        }
+        let mut cost = 0;
+        for index in indexes {
+            let entry = &self.entries[*index];
+            if entry.root == target {
+                continue;
+            }
+            moves.extend(entry.moves(target, self.scan.symlinks));
+            cost += entry.size;
+            let (source, subdir, size) = (entry.root.clone(), entry.subdir.clone(), entry.size);
+            if let Some(fs) = self.roots.get_mut(&source) {
+                fs.blocks_available += fs.blocks(size);
+                fs.inodes_available += 1;
+            }
+            if let Some(fs) = self.roots.get_mut(target) {
+                fs.blocks_available -= fs.blocks(size);
+                fs.inodes_available = fs.inodes_available.saturating_sub(1);
+            }
+            let usage = self.usage.entry(subdir).or_default();
+            *usage.entry(source).or_default() -= 1;
+            *usage.entry(target.to_path_buf()).or_default() += 1;
+            self.entries[*index].root = target.to_path_buf();
+        }
+        cost
+    }
+
+    /// Move whole subdirs, each at most once and the largest first, from the fullest
+    /// root to the emptiest, until free space is balanced or no move narrows the gap.
+    fn greedy_rebalance(
+        &mut self,
+        groups: &BTreeMap<&Path, Vec<usize>>,
+        moves: &mut Vec<Move>,
+    ) -> u64 {
+        if !matches!(self.objective, Objective::Rebalance { .. }) {
+            return 0;
+        }
+        let mut cost = 0;
+        let mut moved = HashSet::new();
+        while !self.objective_met() {
+            let mut free = self
+                .roots
+                .iter()
+                .filter(|(_, fs)| fs.keeps_files())
+                .filter_map(|(root, fs)| Some((fs.free_basis_points()?, root.clone())))
+                .collect::<Vec<_>>();
+            free.sort();
+            let (Some((fullest_free, fullest)), Some((emptiest_free, emptiest))) =
+                (free.first().cloned(), free.last().cloned())
+            else {
+                break;
+            };
+            let spread = emptiest_free - fullest_free;
+            let candidate = groups
+                .iter()
+                .filter(|(subdir, indexes)| {
+                    !moved.contains(*subdir)
+                        && indexes
+                            .iter()
+                            .all(|index| self.entries[*index].root == fullest)
+                        && self.scan.constraints.may_receive(subdir, &emptiest)
+                        && self.fits(indexes, &emptiest)
+                        && self.narrows(indexes, &fullest, &emptiest, spread)
+                })
+                .max_by_key(|(subdir, indexes)| (self.bytes(indexes), Reverse(*subdir)));
+            let Some((subdir, indexes)) = candidate else {
+                warn!(
+                    "Free space left {} basis points apart: moving no whole subdir narrows it",
+                    spread
+                );
+                break;
+            };
+            moved.insert(*subdir);
+            cost += self.shift(indexes, &emptiest, moves);
+        }
+        cost
+    }
+
+    /// Whether moving the entries from `fullest` to `emptiest` leaves them less than `spread` apart.
+    fn narrows(&self, indexes: &[usize], fullest: &Path, emptiest: &Path, spread: u64) -> bool {
+        let mut from = self.roots[fullest].clone();
+        let mut to = self.roots[emptiest].clone();
+        for index in indexes {
+            let size = self.entries[*index].size;
+            from.blocks_available += from.blocks(size);
+            to.blocks_available = to.blocks_available.saturating_sub(to.blocks(size));
+        }
+        match (from.free_basis_points(), to.free_basis_points()) {
+            (Some(from), Some(to)) => from.abs_diff(to) < spread,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        state::{fixture, Objective},
+        Budget, Constraints, Decompose, Move, Strategy,
+    };
+
+    fn moved(source: &str, target: &str) -> Move {
+        Move {
+            source: PathBuf::from(source),
+            target: PathBuf::from(target),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn gathers_onto_majority_root() {
+        let state = fixture(
+            &[("a", 10), ("b", 10)],
+            &[
+                ("a", "A", "1", 10),
+                ("a", "A", "2", 10),
+                ("b", "A", "3", 5),
+                ("b", "B", "4", 4096),
+            ],
+        );
+        let (moves, cost) = state.greedy().unwrap();
+        assert_eq!(5, cost);
+        assert_eq!(vec![moved("b/A/3", "a/A/3")], moves);
+        assert_eq!(Some(cost), state.relocate().map(|(_, cost)| cost));
+    }
+
+    #[test]
+    fn majority_root_full() {
+        let state = fixture(
+            &[("a", 0), ("b", 10)],
+            &[("a", "A", "1", 10), ("a", "A", "2", 10), ("b", "A", "3", 5)],
+        );
+        let (moves, cost) = state.greedy().unwrap();
+        assert_eq!(20, cost);
+        assert_eq!(
+            vec![moved("a/A/1", "b/A/1"), moved("a/A/2", "b/A/2")],
+            moves
+        );
+    }
+
+    #[test]
+    fn retries_once_room_is_freed() {
+        // X is placed first, but only fits on a once Y has moved off it
+        let state = fixture(
+            &[("a", 1), ("b", 4)],
+            &[
+                ("a", "X", "1", 4 * 4096),
+                ("b", "X", "2", 4096),
+                ("a", "Y", "3", 4096),
+                ("b", "Y", "4", 2 * 4096),
+            ],
+        );
+        let (moves, cost) = state.greedy().unwrap();
+        assert_eq!(2 * 4096, cost);
+        assert_eq!(
+            vec![moved("a/Y/3", "b/Y/3"), moved("b/X/2", "a/X/2")],
+            moves
+        );
+    }
+
+    #[test]
+    fn counts_directories_made() {
+        // Gathering onto a makes A/x there as well as moving two files
+        let state = |inodes| {
+            let mut state = fixture(
+                &[("a", 10), ("b", 10)],
+                &[
+                    ("a", "A", "1", 20),
+                    ("b", "A", "2", 5),
+                    ("b", "A", "x/3", 5),
+                ],
+            );
+            let fs = state.roots.get_mut(&PathBuf::from("a")).unwrap();
+            *fs = fs.clone().with_inodes(inodes, 100);
+            state
+        };
+        let (moves, cost) = state(4).greedy().unwrap();
+        assert_eq!(10, cost);
+        assert_eq!(
+            vec![moved("b/A/2", "a/A/2"), moved("b/A/x/3", "a/A/x/3")],
+            moves
+        );
+        let (moves, cost) = state(3).greedy().unwrap();
+        assert_eq!(20, cost);
+        assert_eq!(vec![moved("a/A/1", "b/A/1")], moves);
+    }
+
+    #[test]
+    fn no_room() {
+        let state = fixture(
+            &[("a", 1), ("b", 1)],
+            &[("a", "A", "1", 4096), ("b", "A", "2", 4096)],
+        );
+        assert_eq!(None, state.greedy());
+    }
+
+    #[test]
+    fn drained_and_constrained() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 10), ("d", 10)],
+            &[
+                ("d", "A", "1", 10),
+                ("a", "A", "2", 10),
+                ("b", "B", "3", 10),
+                ("b", "B", "4", 10),
+            ],
+        );
+        let d = state.roots.get_mut(&PathBuf::from("d")).unwrap();
+        *d = d.clone().with_drain(true);
+        std::sync::Arc::make_mut(&mut state.scan).constraints =
+            Constraints::default().forbid("B", "b");
+        let (mut moves, cost) = state.greedy().unwrap();
+        moves.sort_by(|a, b| a.source.cmp(&b.source));
+        assert_eq!(30, cost);
+        assert_eq!(
+            vec![
+                moved("b/B/3", "a/B/3"),
+                moved("b/B/4", "a/B/4"),
+                moved("d/A/1", "a/A/1"),
+            ],
+            moves
+        );
+    }
+
+    #[test]
+    fn rebalances_whole_subdirs() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 90)],
+            &[("a", "A", "1", 39 * 4096), ("a", "B", "2", 10)],
+        );
+        state.set_objective(Objective::Rebalance { tolerance: 1000 });
+        let (moves, cost) = state.greedy().unwrap();
+        assert_eq!(39 * 4096, cost);
+        assert_eq!(vec![moved("a/A/1", "b/A/1")], moves);
+    }
+
+    #[test]
+    fn large_tree() {
+        // A thousand subdirs of a hundred files, each with a tenth away from the rest
+        let roots = ["a", "b", "c"];
+        let names = (0..1000)
+            .flat_map(|subdir| (0..100).map(move |file| (subdir, file)))
+            .map(|(subdir, file)| (subdir, format!("S{subdir}"), format!("{file}")))
+            .collect::<Vec<_>>();
+        let entries = names
+            .iter()
+            .map(|(subdir, name, file)| {
+                let root = if file.len() == 1 {
+                    roots[(subdir + 1) % 3]
+                } else {
+                    roots[subdir % 3]
+                };
+                (root, name.as_str(), file.as_str(), 4096)
+            })
+            .collect::<Vec<_>>();
+        let state = fixture(&[("a", 1 << 20), ("b", 1 << 20), ("c", 1 << 20)], &entries);
+        assert_eq!(100_000, state.entries.len());
+
+        let started = Instant::now();
+        let planner = Decompose(Strategy::Greedy.planner(0, Budget::default()));
+        let plan = state.plan_with(&planner).unwrap();
+        assert!(
+            started.elapsed() < Duration::from_secs(60),
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(1000 * 10 * 4096, plan.cost);
+        // The tenth of each subdir, then the directory it leaves
+        assert_eq!(1000 * 11, plan.moves.len());
+        assert!(plan.split.is_empty());
+        assert!(plan.moves.iter().filter(|m| !m.directory).all(|m| {
+            let subdir = m.source.iter().nth(1).unwrap().to_str().unwrap();
+            m.target
+                .starts_with(roots[subdir[1..].parse::<usize>().unwrap() % 3])
+        }));
+    }
+}
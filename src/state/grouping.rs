@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+
+/// Name of the file marking a directory as a unit, by default.
+pub const DEFAULT_MARKER: &str = ".relocation-unit";
+
+/// How files are grouped into subdirs, each of which is relocated as a whole.
+///
+/// Files which a strategy does not place in any group are grouped by their first
+/// path component below the root, as by `Depth(1)`.
+#[derive(Debug, Clone)]
+pub enum Grouping {
+    /// The first `n` path components below the root.
+    Depth(usize),
+    /// The outermost directory containing a file of this name.
+    Marker(OsString),
+    /// The path below the root up to the end of the first capture group (or of the
+    /// whole match, if the pattern has none), if that falls between components.
+    Regex(Regex),
+}
+
+impl Default for Grouping {
+    fn default() -> Self {
+        Grouping::Depth(1)
+    }
+}
+
+impl PartialEq for Grouping {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Grouping::Depth(a), Grouping::Depth(b)) => a == b,
+            (Grouping::Marker(a), Grouping::Marker(b)) => a == b,
+            (Grouping::Regex(a), Grouping::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Grouping {}
+
+impl Grouping {
+    /// Split a path relative to its root into its subdir and the path within that.
+    ///
+    /// A file name is never part of the subdir, though a directory's own name may be. A
+    /// directory is marked if it holds a marker within any of `roots`, so that a path is
+    /// grouped alike whichever root it is on. `markers` caches, per directory relative
+    /// to the roots, whether it is marked.
+    pub(crate) fn split<'a>(
+        &self,
+        roots: impl Iterator<Item = &'a PathBuf> + Clone,
+        relative: &Path,
+        is_dir: bool,
+        markers: &mut HashMap<PathBuf, bool>,
+    ) -> (PathBuf, PathBuf) {
+        let depth = self
+            .depth(roots, relative, is_dir, markers)
+            .unwrap_or(1)
+            .min(
+                relative
+                    .components()
+                    .count()
+                    .saturating_sub(usize::from(!is_dir)),
+            );
+        let subdir = relative.components().take(depth).collect::<PathBuf>();
+        let subpath = relative.components().skip(depth).collect::<PathBuf>();
+        (subdir, subpath)
+    }
+
+    /// Number of leading components of `relative` forming its subdir, if grouped.
+    fn depth<'a>(
+        &self,
+        roots: impl Iterator<Item = &'a PathBuf> + Clone,
+        relative: &Path,
+        is_dir: bool,
+        markers: &mut HashMap<PathBuf, bool>,
+    ) -> Option<usize> {
+        match self {
+            Grouping::Depth(depth) => Some(*depth),
+            Grouping::Marker(name) => relative
+                .ancestors()
+                .skip(usize::from(!is_dir))
+                .filter(|dir| {
+                    *markers.entry(dir.to_path_buf()).or_insert_with(|| {
+                        roots
+                            .clone()
+                            .any(|root| root.join(dir).join(name).is_file())
+                    })
+                })
+                .last()
+                .map(|dir| dir.components().count()),
+            Grouping::Regex(regex) => {
+                let text = relative.to_str()?;
+                let captures = regex.captures(text)?;
+                let end = captures.get(1).or_else(|| captures.get(0))?.end();
+                let prefix = Path::new(&text[..end]);
+                // Path::starts_with only matches whole components
+                relative
+                    .starts_with(prefix)
+                    .then(|| prefix.components().count())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, fs, path::PathBuf};
+
+    use regex::Regex;
+
+    use super::{Grouping, DEFAULT_MARKER};
+
+    fn split(grouping: &Grouping, relative: &str) -> (PathBuf, PathBuf) {
+        grouping.split([].iter(), relative.as_ref(), false, &mut HashMap::new())
+    }
+
+    #[test]
+    fn depth() {
+        let grouping = Grouping::Depth(2);
+        assert_eq!(
+            (PathBuf::from("a/b"), PathBuf::from("c/1.txt")),
+            split(&grouping, "a/b/c/1.txt")
+        );
+        assert_eq!(
+            (PathBuf::from("a"), PathBuf::from("1.txt")),
+            split(&grouping, "a/1.txt")
+        );
+        assert_eq!(
+            (PathBuf::new(), PathBuf::from("1.txt")),
+            split(&grouping, "1.txt")
+        );
+        // A directory may be a subdir itself
+        assert_eq!(
+            (PathBuf::from("a/b"), PathBuf::new()),
+            grouping.split([].iter(), "a/b".as_ref(), true, &mut HashMap::new())
+        );
+    }
+
+    #[test]
+    fn regex() {
+        let grouping = Grouping::Regex(Regex::new(r"^Shows/([^/]+)/Season").unwrap());
+        assert_eq!(
+            (PathBuf::from("Shows/X"), PathBuf::from("Season 1/1.mkv")),
+            split(&grouping, "Shows/X/Season 1/1.mkv")
+        );
+        // Unmatched, or not ending between components
+        assert_eq!(
+            (PathBuf::from("Films"), PathBuf::from("Y/1.mkv")),
+            split(&grouping, "Films/Y/1.mkv")
+        );
+        let grouping = Grouping::Regex(Regex::new(r"^Shows/X").unwrap());
+        assert_eq!(
+            (PathBuf::from("Shows"), PathBuf::from("Xtra/1.mkv")),
+            split(&grouping, "Shows/Xtra/1.mkv")
+        );
+    }
+
+    #[test]
+    fn marker() {
+        let test_dir = PathBuf::from("test_dir_grouping_marker");
+        let roots = [test_dir.join("1"), test_dir.join("2")];
+        fs::create_dir_all(roots[0].join("a/b")).unwrap();
+        fs::create_dir_all(roots[1].join("a/b/c")).unwrap();
+        fs::write(roots[0].join("a/b").join(DEFAULT_MARKER), "").unwrap();
+        fs::write(roots[1].join("a/b/c").join(DEFAULT_MARKER), "").unwrap();
+
+        let grouping = Grouping::Marker(DEFAULT_MARKER.into());
+        let mut markers = HashMap::new();
+        // The outermost directory marked within any root wins
+        assert_eq!(
+            (PathBuf::from("a/b"), PathBuf::from("c/1.txt")),
+            grouping.split(roots.iter(), "a/b/c/1.txt".as_ref(), false, &mut markers)
+        );
+        assert_eq!(
+            (PathBuf::from("a"), PathBuf::from("d/1.txt")),
+            grouping.split(roots.iter(), "a/d/1.txt".as_ref(), false, &mut markers)
+        );
+
+        fs::remove_dir_all(test_dir).unwrap();
+    }
+}
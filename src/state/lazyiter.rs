@@ -1,14 +1,20 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use log::{debug, info};
 
-use crate::{filesystem::FileSystem, Entry, State};
+use crate::{
+    filesystem::FileSystem,
+    state::{Objective, Scan},
+    Entry, State,
+};
 
 #[derive(Debug)]
 pub struct LazySuccessors {
     roots: Vec<(PathBuf, FileSystem)>,
     entries: Vec<Entry>,
     usage: HashMap<PathBuf, HashMap<PathBuf, u64>>,
+    objective: Objective,
+    scan: Arc<Scan>,
     cur_entry_idx: usize,
     cur_root_idx: usize,
     //state: State,
@@ -31,6 +37,7 @@ impl From<&State> for LazySuccessors {
             .collect();
         let entries = state.entries.clone();
         let usage = state.usage.clone();
+        let objective = state.objective.clone();
         Self {
             //state: state.to_owned(),
             cur_entry_idx: 0,
@@ -38,6 +45,8 @@ impl From<&State> for LazySuccessors {
             roots,
             entries,
             usage,
+            objective,
+            scan: Arc::clone(&state.scan),
         }
         // let roots = state.roots.clone();
         // let entries = state.entries.clone();
@@ -67,20 +76,42 @@ impl Iterator for LazySuccessors {
             let cur_root = self.roots.get(self.cur_root_idx);
             self.cur_root_idx += 1;
             if let Some(cur_root) = cur_root {
-                // TODO skip if insufficient space in 'cur_root'
-                if cur_root.1.blocks_available < cur_root.1.blocks(cur_entry.size) {
+                if *cur_root.0 == cur_entry.root
+                    || cur_root.1.drain
+                    || !self
+                        .scan
+                        .constraints
+                        .may_receive(&cur_entry.subdir, &cur_root.0)
+                {
+                    continue;
+                }
+                if !cur_root.1.has_room(cur_entry.size) {
                     debug!(
-                        "Cannot move {:?} to {:?} ({} of {} blocks available)",
+                        "Cannot move {:?} to {:?} ({} of {} blocks available, {} reserved)",
                         cur_entry,
                         cur_root,
                         cur_root.1.blocks_available,
-                        cur_root.1.blocks(cur_entry.size)
+                        cur_root.1.blocks(cur_entry.size),
+                        cur_root.1.reserved_blocks
                     );
                     continue;
                 }
-                if *cur_root.0 != cur_entry.root {
-                    break cur_root;
+                let directories = self.scan.directories_made(
+                    [cur_entry],
+                    self.entries
+                        .iter()
+                        .filter(|entry| entry.subdir == cur_entry.subdir),
+                    &cur_root.0,
+                    &self.usage,
+                );
+                if !cur_root.1.has_inodes(1 + directories.len() as u64) {
+                    debug!(
+                        "Cannot move {:?} to {:?} (no inodes available)",
+                        cur_entry, cur_root
+                    );
+                    continue;
                 }
+                break cur_root;
             }
             if cur_root.is_none() {
                 debug!("advance cur_entry");
@@ -99,7 +130,15 @@ impl Iterator for LazySuccessors {
                 .join(&cur_entry.subpath),
             cur_root.0.clone()
         );
-        let state = Self::new_state(cur_entry, &self.entries, &self.roots, cur_root, &self.usage);
+        let state = Self::new_state(
+            cur_entry,
+            &self.entries,
+            &self.roots,
+            cur_root,
+            &self.usage,
+            &self.objective,
+            &self.scan,
+        );
         Some((state, cur_entry.size))
     }
 }
@@ -111,7 +150,15 @@ impl LazySuccessors {
         roots: &[(PathBuf, FileSystem)],
         (other_root, other_filesystem): &(PathBuf, FileSystem),
         usage: &HashMap<PathBuf, HashMap<PathBuf, u64>>,
+        objective: &Objective,
+        scan: &Arc<Scan>,
     ) -> State {
+        let directories = scan.directories_made(
+            [entry],
+            entries.iter().filter(|e| e.subdir == entry.subdir),
+            other_root,
+            usage,
+        );
         let mut entries = entries
             .iter()
             .filter(|e| *e != entry)
@@ -132,13 +179,19 @@ impl LazySuccessors {
             // freeing of blocks
             let freed_blocks = fs.blocks(entry.size);
             debug!("freed {} blocks from {:?}", freed_blocks, entry.root);
-            fs.blocks_available = freed_blocks;
+            fs.blocks_available += freed_blocks;
+            // One inode, however many links the file has
+            fs.inodes_available += 1;
         });
         roots.entry(other_root.to_path_buf()).and_modify(|fs| {
             // consumption of blocks
             let consumed_blocks = fs.blocks(entry.size);
             debug!("consumed {} blocks from {:?}", consumed_blocks, other_root);
             fs.blocks_available -= consumed_blocks;
+            // And one for each directory made for it
+            fs.inodes_available = fs
+                .inodes_available
+                .saturating_sub(1 + directories.len() as u64);
         });
         info!("- new roots: {roots:?}");
         // Modify usage
@@ -164,6 +217,8 @@ impl LazySuccessors {
             entries,
             roots,
             usage,
+            objective: objective.to_owned(),
+            scan: Arc::clone(scan),
         }
     }
 }
@@ -175,6 +230,8 @@ fn init() {
 
     setup_logger(true);
 }
+
+#[cfg(test)]
 mod test {
     use std::{collections::HashMap, path::PathBuf};
 
@@ -190,6 +247,7 @@ mod test {
             roots: HashMap::new(),
             entries: Vec::new(),
             usage: HashMap::new(),
+            ..Default::default()
         };
         assert_eq!(0, LazySuccessors::from(&state).count());
     }
@@ -206,6 +264,7 @@ mod test {
             root: PathBuf::from("a"),
             subdir: PathBuf::from(""),
             subpath: PathBuf::from("test"),
+            ..Default::default()
         }];
         let usage = entries.iter().fold(
             HashMap::<PathBuf, HashMap<PathBuf, u64>>::new(),
@@ -221,6 +280,7 @@ mod test {
             roots,
             entries,
             usage,
+            ..Default::default()
         };
         assert_eq!(1, LazySuccessors::from(&state).count());
     }
@@ -238,12 +298,14 @@ mod test {
                 root: PathBuf::from("a"),
                 subdir: PathBuf::from(""),
                 subpath: PathBuf::from("test"),
+                ..Default::default()
             },
             Entry {
                 size: 10,
                 root: PathBuf::from("b"),
                 subdir: PathBuf::from(""),
                 subpath: PathBuf::from("test2"),
+                ..Default::default()
             },
         ];
         let usage = entries.iter().fold(
@@ -260,6 +322,7 @@ mod test {
             roots,
             entries,
             usage,
+            ..Default::default()
         };
         assert_eq!(2, LazySuccessors::from(&state).count());
     }
@@ -282,24 +345,28 @@ mod test {
                 root: PathBuf::from("a"),
                 subdir: PathBuf::from(""),
                 subpath: PathBuf::from("test"),
+                ..Default::default()
             },
             Entry {
                 size: 10,
                 root: PathBuf::from("a"),
                 subdir: PathBuf::from(""),
                 subpath: PathBuf::from("test2"),
+                ..Default::default()
             },
             Entry {
                 size: 10,
                 root: PathBuf::from("b"),
                 subdir: PathBuf::from(""),
                 subpath: PathBuf::from("test3"),
+                ..Default::default()
             },
             Entry {
                 size: 10,
                 root: PathBuf::from("b"),
                 subdir: PathBuf::from(""),
                 subpath: PathBuf::from("test4"),
+                ..Default::default()
             },
         ];
         let usage = entries.iter().fold(
@@ -316,6 +383,7 @@ mod test {
             roots,
             entries,
             usage,
+            ..Default::default()
         };
         assert_eq!(0, LazySuccessors::from(&state).count());
     }
@@ -338,24 +406,28 @@ mod test {
                 root: PathBuf::from("a"),
                 subdir: PathBuf::from(""),
                 subpath: PathBuf::from("test"),
+                ..Default::default()
             },
             Entry {
                 size: 10,
                 root: PathBuf::from("a"),
                 subdir: PathBuf::from(""),
                 subpath: PathBuf::from("test2"),
+                ..Default::default()
             },
             Entry {
                 size: 10,
                 root: PathBuf::from("b"),
                 subdir: PathBuf::from(""),
                 subpath: PathBuf::from("test3"),
+                ..Default::default()
             },
             Entry {
                 size: 10,
                 root: PathBuf::from("b"),
                 subdir: PathBuf::from(""),
                 subpath: PathBuf::from("test4"),
+                ..Default::default()
             },
         ];
         let usage = entries.iter().fold(
@@ -372,6 +444,7 @@ mod test {
             roots,
             entries,
             usage,
+            ..Default::default()
         };
         assert_eq!(4, LazySuccessors::from(&state).count());
     }
@@ -394,24 +467,28 @@ mod test {
                 root: PathBuf::from("a"),
                 subdir: PathBuf::from("A"),
                 subpath: PathBuf::from("test"),
+                ..Default::default()
             },
             Entry {
                 size: 10,
                 root: PathBuf::from("a"),
                 subdir: PathBuf::from("B"),
                 subpath: PathBuf::from("test2"),
+                ..Default::default()
             },
             Entry {
                 size: 10,
                 root: PathBuf::from("b"),
                 subdir: PathBuf::from("A"),
                 subpath: PathBuf::from("test3"),
+                ..Default::default()
             },
             Entry {
                 size: 10,
                 root: PathBuf::from("b"),
                 subdir: PathBuf::from("B"),
                 subpath: PathBuf::from("test4"),
+                ..Default::default()
             },
         ];
         let usage = entries.iter().fold(
@@ -428,6 +505,7 @@ mod test {
             roots,
             entries,
             usage,
+            ..Default::default()
         };
         assert_eq!(4, LazySuccessors::from(&state).count());
     }
@@ -0,0 +1,147 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::{Path, PathBuf},
+};
+
+use log::{error, info, warn};
+
+use crate::{Move, State};
+
+impl State {
+    /// Whether every root is on the same device, so that any move is a rename.
+    pub(crate) fn single_filesystem(&self) -> bool {
+        let mut ids = self.roots.values().map(|fs| fs.id);
+        match ids.next() {
+            Some(id) => ids.all(|other| other == id),
+            None => true,
+        }
+    }
+
+    /// Merge roots sharing a filesystem, by renaming the largest possible directories.
+    ///
+    /// No data is copied, so the cost is the number of renames rather than bytes.
+    pub(crate) fn merge(&self) -> Option<(Vec<Move>, u64)> {
+        let subdirs = self
+            .entries
+            .iter()
+            .map(|e| &e.subdir)
+            .collect::<BTreeSet<_>>();
+        let mut candidates = self
+            .roots
+            .iter()
+            .filter(|(_, fs)| fs.keeps_files())
+            .map(|(root, _)| root)
+            .collect::<Vec<_>>();
+        candidates.sort();
+
+        let mut moves = Vec::new();
+        let mut unplaced = Vec::new();
+        for subdir in subdirs {
+            let constraints = &self.scan.constraints;
+            let Some((target, renames)) = candidates
+                .iter()
+                .filter(|target| constraints.may_hold(subdir, target))
+                .map(|target| (*target, self.merge_renames(subdir, target)))
+                .filter(|(target, renames)| {
+                    renames.is_empty() || constraints.may_receive(subdir, target)
+                })
+                .min_by_key(|(_, renames)| renames.len())
+            else {
+                unplaced.push(subdir);
+                continue;
+            };
+            moves.extend(renames.into_iter().map(|(root, unit)| {
+                let symlink = self
+                    .entries
+                    .iter()
+                    .find(|e| e.root == root && e.path() == unit)
+                    .and_then(|e| e.retarget(target, self.scan.symlinks));
+                Move {
+                    source: root.join(&unit),
+                    target: target.join(&unit),
+                    hard_link: None,
+                    symlink,
+                    directory: false,
+                }
+            }));
+        }
+        if !unplaced.is_empty() {
+            warn!("No root may hold all of {:?}, so left in place", unplaced);
+        } else if moves.is_empty() {
+            info!("Already fully merged");
+            return None;
+        }
+        let cost = moves.len() as u64;
+        info!(
+            "Merge solution found, with {} renames: {:?}",
+            moves.len(),
+            moves
+        );
+        Some((moves, cost))
+    }
+
+    /// Renames (root, path within root) merging `subdir` from every other root into `target`.
+    fn merge_renames(&self, subdir: &Path, target: &Path) -> BTreeSet<(PathBuf, PathBuf)> {
+        let entries = self.entries.iter().filter(|e| e.subdir == subdir);
+        // Every directory and file already within the target
+        let present = entries
+            .clone()
+            .filter(|e| e.root == target)
+            .flat_map(|e| e.paths())
+            .flat_map(|path| path.ancestors().map(Path::to_path_buf).collect::<Vec<_>>())
+            .collect::<HashSet<_>>();
+        // Symbolic links which must be rewritten, so cannot be renamed along with their directory
+        let rewritten = entries
+            .clone()
+            .filter(|e| e.root != target && e.retarget(target, self.scan.symlinks).is_some())
+            .collect::<Vec<_>>();
+        // Every directory holding files of other subdirs, excluded paths, or links to
+        // rewrite, which must stay put
+        let shared = self
+            .entries
+            .iter()
+            .filter(|e| e.root != target && e.subdir != subdir)
+            .flat_map(|e| e.paths().map(move |path| (&e.root, path)))
+            .chain(rewritten.iter().map(|e| (&e.root, e.path())))
+            .chain(
+                self.scan
+                    .excluded
+                    .iter()
+                    .map(|(root, path)| (root, path.clone())),
+            )
+            .flat_map(|(root, path)| {
+                path.ancestors()
+                    .map(|p| (root.clone(), p.to_path_buf()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<HashSet<_>>();
+        let mut renames = BTreeSet::new();
+        let paths = entries
+            .filter(|e| e.root != target)
+            .flat_map(|e| e.paths().map(move |path| (e, path)));
+        for (entry, path) in paths {
+            if rewritten.contains(&entry) && path == entry.path() {
+                renames.insert((entry.root.clone(), path));
+                continue;
+            }
+            // The outermost ancestor missing from the target can be renamed whole
+            let unit = path
+                .ancestors()
+                .filter(|p| !p.as_os_str().is_empty())
+                .filter(|p| !present.contains(*p))
+                .filter(|p| !shared.contains(&(entry.root.clone(), p.to_path_buf())))
+                .last();
+            match unit {
+                Some(unit) => {
+                    renames.insert((entry.root.clone(), unit.to_path_buf()));
+                }
+                None => error!(
+                    "Cannot merge {:?} into {:?}: already present",
+                    entry.root.join(&path),
+                    target
+                ),
+            }
+        }
+        renames
+    }
+}
@@ -1,7 +1,65 @@
 mod basiciter;
+mod budget;
+mod constraints;
+mod decompose;
+mod directories;
+mod exact;
+mod filter;
+mod greedy;
+mod grouping;
 mod lazyiter;
+mod merge;
+mod objective;
+mod planner;
+mod scan;
 mod status;
+mod symlink;
 
 pub use basiciter::ExistingSuccessors;
+pub use budget::{Allowance, Budget};
+pub(crate) use constraints::parse_placement;
+pub use constraints::Constraints;
+pub use decompose::Decompose;
+pub use exact::{Bounded, BranchAndBound};
+pub use filter::{Filter, DEFAULT_IGNORE_FILE};
+pub use grouping::{Grouping, DEFAULT_MARKER};
 pub use lazyiter::LazySuccessors;
+pub use objective::Objective;
+pub use planner::{AStar, Beam, Greedy, IdaStar, Planner, Strategy, DEFAULT_BEAM_WIDTH};
+pub use scan::{Scan, ScanError, SkipReason, Skipped};
 pub use status::{Entry, Move, State};
+pub use symlink::SymlinkPolicy;
+
+/// A state with `roots` (path, blocks available), each its own filesystem of 100 blocks,
+/// holding `entries` (root, subdir, subpath, size).
+#[cfg(test)]
+pub(crate) fn fixture(roots: &[(&str, u64)], entries: &[(&str, &str, &str, u64)]) -> State {
+    use std::path::PathBuf;
+
+    use crate::filesystem::FileSystem;
+
+    let mut state = State {
+        roots: roots
+            .iter()
+            .enumerate()
+            .map(|(id, (root, blocks_available))| {
+                (
+                    PathBuf::from(root),
+                    FileSystem::new(id as u64, 4096, *blocks_available, false)
+                        .with_blocks_total(100),
+                )
+            })
+            .collect(),
+        ..Default::default()
+    };
+    for (root, subdir, subpath, size) in entries {
+        state.add_entry(Entry {
+            size: *size,
+            root: PathBuf::from(root),
+            subdir: PathBuf::from(subdir),
+            subpath: PathBuf::from(subpath),
+            ..Default::default()
+        });
+    }
+    state
+}
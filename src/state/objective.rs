@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+
+use crate::State;
+
+/// What a relocation must achieve, beyond each subdir ending up on a single root.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub enum Objective {
+    /// Gather each subdir onto whichever root needs the fewest bytes moved.
+    #[default]
+    Consolidate,
+    /// Also even out free space, so that the free-space percentages of all
+    /// filesystems end within `tolerance` basis points (hundredths of a
+    /// percent) of each other.
+    Rebalance { tolerance: u64 },
+}
+
+impl State {
+    pub fn set_objective(&mut self, objective: Objective) {
+        self.objective = objective;
+    }
+
+    /// Free space (in basis points) and capacity (in bytes) of each distinct filesystem of known size which keeps files.
+    fn free_space(&self) -> Vec<(u64, u64)> {
+        let mut roots = self.roots.iter().collect::<Vec<_>>();
+        roots.sort_by_key(|(root, _)| *root);
+        roots
+            .into_iter()
+            .filter(|(_, fs)| fs.keeps_files())
+            .filter_map(|(_, fs)| Some((fs.id, (fs.free_basis_points()?, fs.total_bytes()))))
+            .collect::<BTreeMap<_, _>>()
+            .into_values()
+            .collect()
+    }
+
+    /// Whether the parts of the objective beyond consolidation are met.
+    pub(crate) fn objective_met(&self) -> bool {
+        match self.objective {
+            Objective::Consolidate => true,
+            Objective::Rebalance { tolerance } => self.balance_gap(tolerance).is_none(),
+        }
+    }
+
+    /// Lower bound on the bytes still to move to meet the parts of the objective beyond consolidation.
+    pub(crate) fn objective_heuristic(&self) -> u64 {
+        match self.objective {
+            Objective::Consolidate => 0,
+            Objective::Rebalance { tolerance } => match self.balance_gap(tolerance) {
+                // Each byte moved can at best both free space on the fullest
+                // filesystem and consume it on the emptiest
+                Some((gap, fullest, emptiest)) => {
+                    let (fullest, emptiest) = (fullest as u128, emptiest as u128);
+                    (gap as u128 * fullest * emptiest / ((fullest + emptiest) * 10_000)) as u64
+                }
+                None => 0,
+            },
+        }
+    }
+
+    /// How far (in basis points) the free-space spread exceeds `tolerance`, with the
+    /// capacities of the fullest and emptiest filesystems; `None` if within tolerance.
+    fn balance_gap(&self, tolerance: u64) -> Option<(u64, u64, u64)> {
+        let free_space = self.free_space();
+        let (fullest_free, fullest_total) = free_space.iter().min()?;
+        let (emptiest_free, emptiest_total) = free_space.iter().max()?;
+        let spread = emptiest_free - fullest_free;
+        if spread <= tolerance {
+            return None;
+        }
+        Some((spread - tolerance, *fullest_total, *emptiest_total))
+    }
+}
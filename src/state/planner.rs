@@ -0,0 +1,395 @@
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+};
+
+use log::{error, info};
+use pathfinding::prelude::{astar, idastar};
+
+use crate::{
+    state::{
+        budget::{Allowance, Tracker},
+        Budget,
+    },
+    BranchAndBound, Move, State,
+};
+
+/// Beam width used unless told otherwise.
+pub const DEFAULT_BEAM_WIDTH: usize = 64;
+
+/// A way of relocating a state's entries between filesystems.
+///
+/// Implementations may build on [`State::successors`], [`State::heuristic`] and
+/// [`State::success`], turning the path found into moves with [`State::calculate_moves`].
+pub trait Planner {
+    /// The moves relocating `state`'s entries, with their cost; or, if a budget ran out
+    /// first, those of the best partial relocation found; or `None` if neither was found.
+    fn relocate(&self, state: &State) -> Option<(Vec<Move>, u64)> {
+        self.relocate_within(state, &mut Allowance::new(self.budget()))
+    }
+
+    /// As [`Planner::relocate`], drawing on `allowance`, which other searches may share,
+    /// rather than on the planner's own budget.
+    fn relocate_within(&self, state: &State, allowance: &mut Allowance)
+        -> Option<(Vec<Move>, u64)>;
+
+    /// The limits on the planner's searches; none, unless it searches.
+    fn budget(&self) -> Budget {
+        Budget::default()
+    }
+
+    /// As [`Planner::relocate`], with the least cost any relocation could have, where
+    /// the planner proves one.
+    fn relocate_bounded(&self, state: &State) -> Option<(Vec<Move>, u64, Option<u64>)> {
+        let (moves, cost) = self.relocate(state)?;
+        Some((moves, cost, None))
+    }
+}
+
+impl<P: Planner + ?Sized> Planner for Box<P> {
+    fn relocate(&self, state: &State) -> Option<(Vec<Move>, u64)> {
+        (**self).relocate(state)
+    }
+
+    fn relocate_within(
+        &self,
+        state: &State,
+        allowance: &mut Allowance,
+    ) -> Option<(Vec<Move>, u64)> {
+        (**self).relocate_within(state, allowance)
+    }
+
+    fn budget(&self) -> Budget {
+        (**self).budget()
+    }
+
+    fn relocate_bounded(&self, state: &State) -> Option<(Vec<Move>, u64, Option<u64>)> {
+        (**self).relocate_bounded(state)
+    }
+}
+
+/// Iterative deepening A* over single-file moves: the fewest bytes moved, in little
+/// memory, but only practical for small trees.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IdaStar {
+    pub budget: Budget,
+}
+
+/// A* over single-file moves, never expanding a placement of the files twice: the
+/// fewest bytes moved, trading memory for less repeated work than [`IdaStar`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AStar {
+    pub budget: Budget,
+}
+
+/// First-fit decreasing over whole subdirs: fast enough for any tree, though not
+/// always the fewest bytes moved.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Greedy;
+
+/// Breadth-first search over single-file moves, keeping only the `width` most
+/// promising states at each depth, until none could beat the cheapest relocation
+/// found: bounded work, but possibly neither the fewest bytes moved nor any
+/// relocation at all.
+#[derive(Debug, Clone, Copy)]
+pub struct Beam {
+    pub width: usize,
+    pub budget: Budget,
+}
+
+impl Default for Beam {
+    fn default() -> Self {
+        Self {
+            width: DEFAULT_BEAM_WIDTH,
+            budget: Budget::default(),
+        }
+    }
+}
+
+impl Planner for IdaStar {
+    fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    fn relocate_within(
+        &self,
+        state: &State,
+        allowance: &mut Allowance,
+    ) -> Option<(Vec<Move>, u64)> {
+        let mut tracker = Tracker::new(allowance);
+        match idastar(
+            state,
+            |s| tracker.successors(s),
+            |s| s.heuristic(),
+            |s| s.success(),
+        ) {
+            Some((states, cost)) => found(&states, cost),
+            None => tracker.partial(state).or_else(not_found),
+        }
+    }
+}
+
+impl Planner for AStar {
+    fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    fn relocate_within(
+        &self,
+        state: &State,
+        allowance: &mut Allowance,
+    ) -> Option<(Vec<Move>, u64)> {
+        let mut tracker = Tracker::new(allowance);
+        match astar(
+            &Placement::from(state.clone()),
+            |p| {
+                tracker
+                    .successors(&p.0)
+                    .map(|(s, cost)| (Placement::from(s), cost))
+            },
+            |p| p.0.heuristic(),
+            |p| p.0.success(),
+        ) {
+            Some((placements, cost)) => {
+                let states = placements.into_iter().map(|p| p.0).collect::<Vec<_>>();
+                found(&states, cost)
+            }
+            None => tracker.partial(state).or_else(not_found),
+        }
+    }
+}
+
+impl Planner for Greedy {
+    fn relocate_within(&self, state: &State, _: &mut Allowance) -> Option<(Vec<Move>, u64)> {
+        state.greedy()
+    }
+}
+
+impl Planner for Beam {
+    fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    fn relocate_within(
+        &self,
+        state: &State,
+        allowance: &mut Allowance,
+    ) -> Option<(Vec<Move>, u64)> {
+        // Each file need move at most once per root
+        let max_depth = state.entries.len() * state.roots.len();
+        let mut tracker = Tracker::new(allowance);
+        let mut best: Option<(Vec<Move>, u64)> = None;
+        let mut beam = vec![(state.clone(), 0, Vec::new())];
+        'search: for _ in 0..max_depth {
+            let bound = best.as_ref().map_or(u64::MAX, |(_, cost)| *cost);
+            let mut next = HashMap::<Vec<(PathBuf, PathBuf)>, (State, u64, Vec<Move>)>::new();
+            for (parent, cost, moves) in &beam {
+                if !tracker.expand(parent) {
+                    break 'search;
+                }
+                for (child, step) in parent.successors() {
+                    let cost = cost + step;
+                    let key = placement(&child);
+                    if cost >= bound
+                        || matches!(next.get(&key), Some((_, best, _)) if *best <= cost)
+                    {
+                        continue;
+                    }
+                    let mut moves = moves.clone();
+                    moves.extend(State::step_moves(parent, &child));
+                    next.insert(key, (child, cost, moves));
+                }
+            }
+            // Successes go no further, and cheaper ones bound the rest of the search; ties
+            // fall to the placement, so the search goes the same way every run
+            let (successes, mut candidates): (Vec<_>, Vec<_>) = next
+                .into_iter()
+                .partition(|(_, (state, ..))| state.success());
+            if let Some((_, (_, cost, moves))) = successes
+                .into_iter()
+                .min_by(|(a, (_, a_cost, _)), (b, (_, b_cost, _))| (a_cost, a).cmp(&(b_cost, b)))
+            {
+                best = Some((moves, cost));
+            }
+            let bound = best.as_ref().map_or(u64::MAX, |(_, cost)| *cost);
+            candidates.retain(|(_, (state, cost, _))| cost + state.heuristic() < bound);
+            candidates.sort_by_cached_key(|(key, (state, cost, _))| {
+                (cost + state.heuristic(), key.clone())
+            });
+            candidates.truncate(self.width);
+            if candidates.is_empty() {
+                break;
+            }
+            beam = candidates
+                .into_iter()
+                .map(|(_, candidate)| candidate)
+                .collect();
+        }
+        let Some((moves, cost)) = best else {
+            return tracker.partial(state).or_else(not_found);
+        };
+        info!(
+            "Relocation solution found, with {} moves, costing {}: {:?}",
+            moves.len(),
+            cost,
+            moves
+        );
+        Some((moves, cost))
+    }
+}
+
+/// A state, equal to another with each file on the same root, whatever the order of its entries.
+#[derive(Debug, Clone)]
+struct Placement(State, Vec<(PathBuf, PathBuf)>);
+
+impl From<State> for Placement {
+    fn from(state: State) -> Self {
+        let key = placement(&state);
+        Self(state, key)
+    }
+}
+
+/// The root of each file, by path within its root.
+fn placement(state: &State) -> Vec<(PathBuf, PathBuf)> {
+    let mut placement = state
+        .entries
+        .iter()
+        .map(|entry| (entry.path(), entry.root.clone()))
+        .collect::<Vec<_>>();
+    placement.sort();
+    placement
+}
+
+impl PartialEq for Placement {
+    fn eq(&self, other: &Self) -> bool {
+        self.1 == other.1
+    }
+}
+
+impl Eq for Placement {}
+
+impl Hash for Placement {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.1.hash(state);
+    }
+}
+
+fn not_found<T>() -> Option<T> {
+    error!("No complete relocation found. Possibly try each subdir in turn.");
+    None
+}
+
+fn found(states: &[State], cost: u64) -> Option<(Vec<Move>, u64)> {
+    if states.len() == 1 {
+        info!("Already fully relocated");
+        return None;
+    }
+    let moves = State::calculate_moves(states);
+    info!(
+        "Relocation solution found, with {} moves, costing {}: {:?}",
+        moves.len(),
+        cost,
+        moves
+    );
+    Some((moves, cost))
+}
+
+/// The planners selectable by name.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum Strategy {
+    /// Iterative deepening A*: the fewest bytes moved, for small trees.
+    #[default]
+    IdaStar,
+    /// A* remembering the states seen: the fewest bytes moved, for small trees.
+    AStar,
+    /// Whole subdirs, largest first, onto the root holding most of each: for large trees.
+    Greedy,
+    /// The most promising states at each depth of the search: for middling trees.
+    Beam,
+    /// Branch and bound over whole subdirs: the fewest bytes moved gathering each
+    /// straight onto one root, for middling trees.
+    Exact,
+}
+
+impl Strategy {
+    /// The planner of this name, searching within `budget`, and `beam_width` states at
+    /// a time if a beam search.
+    pub fn planner(self, beam_width: usize, budget: Budget) -> Box<dyn Planner> {
+        match self {
+            Strategy::IdaStar => Box::new(IdaStar { budget }),
+            Strategy::AStar => Box::new(AStar { budget }),
+            Strategy::Greedy => Box::new(Greedy),
+            Strategy::Beam => Box::new(Beam {
+                width: beam_width,
+                budget,
+            }),
+            Strategy::Exact => Box::new(BranchAndBound { budget }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AStar, Beam, Greedy, IdaStar, Planner, Strategy};
+    use crate::{state::fixture, BranchAndBound, State};
+
+    fn state() -> State {
+        fixture(
+            &[("a", 10), ("b", 10)],
+            &[
+                ("a", "A", "1", 10),
+                ("a", "A", "2", 20),
+                ("b", "A", "3", 40),
+                ("b", "B", "4", 5),
+                ("a", "B", "5", 6),
+            ],
+        )
+    }
+
+    #[test]
+    fn planners_agree() {
+        let state = state();
+        let planners: [&dyn Planner; 5] = [
+            &IdaStar::default(),
+            &AStar::default(),
+            &Greedy,
+            &Beam::default(),
+            &BranchAndBound::default(),
+        ];
+        for planner in planners {
+            let (moves, cost) = state.relocate_with(planner).unwrap();
+            assert_eq!(35, cost);
+            assert_eq!(3, moves.len());
+        }
+    }
+
+    #[test]
+    fn narrow_beam() {
+        let (moves, cost) = state()
+            .relocate_with(&Beam {
+                width: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(cost >= 35);
+        assert!(moves.len() >= 3);
+    }
+
+    #[test]
+    fn by_name() {
+        let state = state();
+        for strategy in [
+            Strategy::IdaStar,
+            Strategy::AStar,
+            Strategy::Greedy,
+            Strategy::Beam,
+            Strategy::Exact,
+        ] {
+            let (_, cost) = state
+                .relocate_with(&*strategy.planner(8, Default::default()))
+                .unwrap();
+            assert_eq!(35, cost);
+        }
+    }
+}
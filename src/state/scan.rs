@@ -0,0 +1,555 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+    ops::AddAssign,
+    os::unix::prelude::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::{debug, error, info, trace, warn};
+use walkdir::WalkDir;
+
+use crate::{
+    filesystem::{FileSystem, Reserve},
+    state::{Constraints, Filter, Grouping, SymlinkPolicy},
+    Entry, State,
+};
+
+/// How roots are scanned, and what was left out of them; shared by every state of a search.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Scan {
+    pub(crate) grouping: Grouping,
+    pub(crate) filter: Filter,
+    pub(crate) symlinks: SymlinkPolicy,
+    /// Paths excluded or skipped, or neither files nor directories, as (root, path
+    /// within root), which must not be moved.
+    pub(crate) excluded: HashSet<(PathBuf, PathBuf)>,
+    /// Directories holding nothing at all, as (root, path within root).
+    pub(crate) empty_dirs: HashSet<(PathBuf, PathBuf)>,
+    /// Whether directories emptied by the moves are left on their source root.
+    pub(crate) keep_source_dirs: bool,
+    pub(crate) constraints: Constraints,
+}
+
+impl Scan {
+    /// Directories missing on `target` that moving `moving` there makes, as a plan is
+    /// checked before it is applied: those holding no entry of `subdir_entries` (each
+    /// of the subdir's entries, wherever they are) on `target`, no other subdir there
+    /// per `usage`, and nothing left out of the scan there.
+    pub(crate) fn directories_made<'a>(
+        &self,
+        moving: impl IntoIterator<Item = &'a Entry>,
+        subdir_entries: impl Iterator<Item = &'a Entry> + Clone,
+        target: &Path,
+        usage: &HashMap<PathBuf, HashMap<PathBuf, u64>>,
+    ) -> HashSet<PathBuf> {
+        let present = |dir: &Path, subdir: &Path| {
+            let held = if dir.starts_with(subdir) {
+                subdir_entries
+                    .clone()
+                    .filter(|entry| entry.root == target)
+                    .any(|entry| entry.paths().any(|path| path.starts_with(dir)))
+            } else {
+                usage.iter().any(|(other, roots)| {
+                    other.starts_with(dir) && roots.get(target).is_some_and(|count| *count > 0)
+                })
+            };
+            held || self
+                .excluded
+                .iter()
+                .chain(&self.empty_dirs)
+                .any(|(root, path)| root == target && path.starts_with(dir))
+        };
+        let mut made = HashSet::new();
+        for entry in moving {
+            for path in entry.paths() {
+                for dir in path.ancestors().skip(1) {
+                    if dir.as_os_str().is_empty() || made.contains(dir) {
+                        continue;
+                    }
+                    if !present(dir, &entry.subdir) {
+                        made.insert(dir.to_path_buf());
+                    }
+                }
+            }
+        }
+        made
+    }
+}
+
+/// A root which could not be scanned at all.
+#[derive(Debug)]
+pub enum ScanError {
+    /// The current directory, against which roots are resolved, is unavailable.
+    CurrentDir(io::Error),
+    /// The root does not exist, or could not be resolved.
+    Root { root: PathBuf, error: io::Error },
+    /// The root is not a directory.
+    NotADirectory(PathBuf),
+    /// The root has already been scanned.
+    Duplicate(PathBuf),
+    /// The filesystem holding the root could not be queried.
+    FileSystem { root: PathBuf, error: io::Error },
+    /// The filter patterns, or the root's ignore file, are invalid.
+    Filter { root: PathBuf, error: ignore::Error },
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanError::CurrentDir(error) => write!(f, "cannot get current directory: {error}"),
+            ScanError::Root { root, error } => write!(f, "cannot resolve {root:?}: {error}"),
+            ScanError::NotADirectory(root) => write!(f, "{root:?} is not a directory"),
+            ScanError::Duplicate(root) => write!(f, "{root:?} already scanned"),
+            ScanError::FileSystem { root, error } => {
+                write!(f, "cannot query filesystem of {root:?}: {error}")
+            }
+            ScanError::Filter { root, error } => write!(f, "invalid filter for {root:?}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+/// Why a path below a root was left out of the scan.
+#[derive(Debug)]
+pub enum SkipReason {
+    Unreadable(io::Error),
+    /// A mount point; its contents belong to another filesystem.
+    OtherDevice,
+    /// A file with hard links outside the scan, which a copy would part from it.
+    LinkedOutside {
+        found: u64,
+        links: u64,
+    },
+    /// A file with hard links in another subdir, which could not stay with both.
+    LinkedAcross(PathBuf),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Unreadable(error) => write!(f, "unreadable: {error}"),
+            SkipReason::OtherDevice => write!(f, "not on the same device as its root"),
+            SkipReason::LinkedOutside { found, links } => {
+                write!(f, "only {found} of its {links} hard links are scanned")
+            }
+            SkipReason::LinkedAcross(subdir) => {
+                write!(f, "hard linked from another subdir, {subdir:?}")
+            }
+        }
+    }
+}
+
+/// A path below a root which was left out of the scan.
+#[derive(Debug)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+impl AddAssign<&str> for State {
+    fn add_assign(&mut self, rhs: &str) {
+        self.scan_or_log(rhs, false);
+    }
+}
+
+impl AddAssign<String> for State {
+    fn add_assign(&mut self, rhs: String) {
+        self.scan_or_log(&rhs, false);
+    }
+}
+
+impl AddAssign<&String> for State {
+    fn add_assign(&mut self, rhs: &String) {
+        self.scan_or_log(rhs, false);
+    }
+}
+
+impl State {
+    /// Add a root usable only to stage files temporarily; it must be empty once relocated.
+    pub fn add_scratch(&mut self, root: &str) {
+        self.scan_or_log(root, true);
+    }
+
+    /// Scan `root` for files, returning the paths below it which had to be skipped.
+    pub fn try_add_root(&mut self, root: &str) -> Result<Vec<Skipped>, ScanError> {
+        self.try_scan(root, false)
+    }
+
+    /// As [`State::add_scratch`], returning the paths below `root` which had to be skipped.
+    pub fn try_add_scratch(&mut self, root: &str) -> Result<Vec<Skipped>, ScanError> {
+        self.try_scan(root, true)
+    }
+
+    /// Add a root to be retired: everything on it must move to the other roots.
+    pub fn add_drained(&mut self, root: &str) {
+        if let Err(e) = self.try_add_drained(root).map(Self::log_skipped) {
+            error!("Error scanning {:?}: {}", root, e);
+        }
+    }
+
+    /// As [`State::add_drained`], returning the paths below `root` which had to be skipped.
+    pub fn try_add_drained(&mut self, root: &str) -> Result<Vec<Skipped>, ScanError> {
+        let skipped = self.try_scan(root, false)?;
+        let path = Self::resolve(root)?;
+        if let Some(filesystem) = self.roots.get_mut(&path) {
+            filesystem.drain = true;
+        }
+        Ok(skipped)
+    }
+
+    /// Group entries by the first `depth` path components below their root, so that
+    /// everything within each such directory is relocated together.
+    ///
+    /// Files shallower than `depth` are grouped by their parent directory.
+    pub fn set_group_depth(&mut self, depth: usize) {
+        self.set_grouping(Grouping::Depth(depth));
+    }
+
+    /// Choose how files are grouped into subdirs; entries already scanned are regrouped.
+    pub fn set_grouping(&mut self, grouping: Grouping) {
+        Arc::make_mut(&mut self.scan).grouping = grouping;
+        self.regroup();
+    }
+
+    /// Choose what becomes of symbolic links moved to another root.
+    pub fn set_symlink_policy(&mut self, policy: SymlinkPolicy) {
+        Arc::make_mut(&mut self.scan).symlinks = policy;
+    }
+
+    /// Leave directories emptied by the moves in place on their source root, rather
+    /// than removing them once their contents have moved.
+    pub fn set_keep_source_dirs(&mut self, keep: bool) {
+        Arc::make_mut(&mut self.scan).keep_source_dirs = keep;
+    }
+
+    /// Leave `reserve` free on every root scanned so far.
+    pub fn set_reserve(&mut self, reserve: Reserve) {
+        for filesystem in self.roots.values_mut() {
+            *filesystem = filesystem.clone().with_reserve(reserve);
+        }
+    }
+
+    /// Leave `reserve` free on `root`, which must already have been scanned.
+    pub fn set_root_reserve(&mut self, root: &str, reserve: Reserve) -> io::Result<()> {
+        let path = self.scanned_root(root)?;
+        let filesystem = self.roots.get_mut(&path).expect("scanned root");
+        *filesystem = filesystem.clone().with_reserve(reserve);
+        Ok(())
+    }
+
+    /// `root` as it is keyed, if it has been scanned.
+    pub(crate) fn scanned_root(&self, root: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let root = root.as_ref();
+        let path = Self::resolve(root).map_err(io::Error::other)?;
+        if !self.roots.contains_key(&path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{root:?} is not a scanned root"),
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Choose which paths are scanned within roots added from now on.
+    pub fn set_filter(&mut self, filter: Filter) {
+        Arc::make_mut(&mut self.scan).filter = filter;
+    }
+
+    fn regroup(&mut self) {
+        let entries = std::mem::take(&mut self.entries);
+        self.usage.clear();
+        let mut markers = HashMap::new();
+        for entry in entries {
+            let (subdir, subpath) =
+                self.scan
+                    .grouping
+                    .split(self.roots.keys(), &entry.path(), false, &mut markers);
+            self.add_entry(Entry {
+                subdir,
+                subpath,
+                ..entry
+            });
+        }
+    }
+
+    /// Forget the entries at `indexes`.
+    fn remove_entries(&mut self, indexes: &HashSet<usize>) {
+        if indexes.is_empty() {
+            return;
+        }
+        for index in indexes {
+            let entry = &self.entries[*index];
+            if let Some(usage) = self.usage.get_mut(&entry.subdir) {
+                if let Some(count) = usage.get_mut(&entry.root) {
+                    *count -= 1;
+                    if *count == 0 {
+                        usage.remove(&entry.root);
+                    }
+                }
+                if usage.is_empty() {
+                    self.usage.remove(&entry.subdir);
+                }
+            }
+        }
+        let mut index = 0;
+        self.entries.retain(|_| {
+            index += 1;
+            !indexes.contains(&(index - 1))
+        });
+    }
+
+    pub(crate) fn add_entry(&mut self, entry: Entry) {
+        *self
+            .usage
+            .entry(entry.subdir.clone())
+            .or_default()
+            .entry(entry.root.clone())
+            .or_default() += 1;
+        self.entries.push(entry);
+    }
+
+    fn scan_or_log(&mut self, root: &str, is_scratchpad: bool) {
+        match self.try_scan(root, is_scratchpad) {
+            Ok(skipped) => Self::log_skipped(skipped),
+            Err(e) => error!("Error scanning {:?}: {}", root, e),
+        }
+    }
+
+    fn log_skipped(skipped: Vec<Skipped>) {
+        for skipped in skipped {
+            warn!("skipping {}: {}", skipped.path.display(), skipped.reason);
+        }
+    }
+
+    /// The absolute, canonical path of `root`, as it is keyed once scanned.
+    fn resolve(root: impl AsRef<Path>) -> Result<PathBuf, ScanError> {
+        let root = root.as_ref();
+        let cur_dir = std::env::current_dir().map_err(ScanError::CurrentDir)?;
+        cur_dir
+            .join(root)
+            .canonicalize()
+            .map_err(|error| ScanError::Root {
+                root: PathBuf::from(root),
+                error,
+            })
+    }
+
+    fn try_scan(&mut self, root: &str, is_scratchpad: bool) -> Result<Vec<Skipped>, ScanError> {
+        let root = Self::resolve(root)?;
+        if self.roots.contains_key(&root) {
+            return Err(ScanError::Duplicate(root));
+        }
+        let root_metadata = root.metadata().map_err(|error| ScanError::Root {
+            root: root.clone(),
+            error,
+        })?;
+        if !root_metadata.is_dir() {
+            return Err(ScanError::NotADirectory(root));
+        }
+        let root_dev_id = root_metadata.dev();
+        let matcher = self
+            .scan
+            .filter
+            .matcher(&root)
+            .map_err(|error| ScanError::Filter {
+                root: root.clone(),
+                error,
+            })?;
+        let filesystem =
+            FileSystem::try_from((root.as_path(), is_scratchpad)).map_err(|error| {
+                ScanError::FileSystem {
+                    root: root.clone(),
+                    error,
+                }
+            })?;
+        self.roots.insert(root.clone(), filesystem);
+        info!("scan {:?}", root);
+
+        let mut skipped = Vec::new();
+        let mut skip = |path: &Path, reason: SkipReason| {
+            debug!("skipping {}: {}", path.display(), reason);
+            skipped.push(Skipped {
+                path: path.to_path_buf(),
+                reason,
+            });
+        };
+        let mut markers = HashMap::new();
+        let mut excluded = Vec::new();
+        // Paths neither files nor directories, left where they are
+        let mut left = Vec::new();
+        let mut dirs = Vec::new();
+        // Directories with anything at all within them
+        let mut occupied = HashSet::new();
+        // Entry index, and number of links, of each file with several hard links
+        let mut inodes = HashMap::new();
+        let walker = WalkDir::new(&root)
+            .same_file_system(true)
+            .into_iter()
+            .filter_entry(|entry| {
+                let is_excluded = entry.depth() > 0
+                    && matcher
+                        .matched(entry.path(), entry.file_type().is_dir())
+                        .is_ignore();
+                if is_excluded {
+                    debug!("excluding {}", entry.path().display());
+                    excluded.push(entry.path().to_path_buf());
+                }
+                !is_excluded
+            });
+        for entry in walker {
+            let (entry, metadata) = match entry.and_then(|entry| {
+                let metadata = entry.metadata()?;
+                Ok((entry, metadata))
+            }) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    let path = e.path().unwrap_or(&root).to_path_buf();
+                    skip(&path, SkipReason::Unreadable(e.into()));
+                    continue;
+                }
+            };
+            let dev_id = metadata.dev();
+            if let Some(parent) = entry.path().parent() {
+                occupied.insert(parent.to_path_buf());
+            }
+
+            trace!(
+                "{:?} {} {:o} {:?} {} {}",
+                dev_id,
+                entry.path().display(),
+                metadata.mode(),
+                metadata.is_dir(),
+                metadata.is_file(),
+                metadata.size(),
+            );
+
+            if dev_id != root_dev_id {
+                skip(entry.path(), SkipReason::OtherDevice);
+                continue;
+            }
+
+            let symlink = if metadata.file_type().is_symlink() {
+                match std::fs::read_link(entry.path()) {
+                    Ok(contents) => Some(contents),
+                    Err(e) => {
+                        skip(entry.path(), SkipReason::Unreadable(e));
+                        continue;
+                    }
+                }
+            } else if metadata.is_file() {
+                None
+            } else if metadata.is_dir() {
+                if entry.depth() > 0 {
+                    dirs.push(entry.path().to_path_buf());
+                }
+                continue;
+            } else {
+                debug!("skipping {}: not a file", entry.path().display());
+                left.push(entry.path().to_path_buf());
+                continue;
+            };
+            // Walked from the root, so always within it
+            let relative = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+            if metadata.nlink() > 1 {
+                let inode = (dev_id, metadata.ino());
+                if let Some(&(index, _)) = inodes.get(&inode) {
+                    let first: &mut Entry = &mut self.entries[index];
+                    debug!(
+                        "{} is a further link to {:?}",
+                        entry.path().display(),
+                        first.path()
+                    );
+                    first.links.push(relative.to_path_buf());
+                    continue;
+                }
+                inodes.insert(inode, (self.entries.len(), metadata.nlink()));
+            }
+            let (subdir, subpath) =
+                self.scan
+                    .grouping
+                    .split(self.roots.keys(), relative, false, &mut markers);
+            debug!(
+                "{:?} {:?} {:?} {:?} {:o} {:?} {} {}",
+                dev_id,
+                root,
+                subdir,
+                subpath,
+                metadata.mode(),
+                metadata.is_dir(),
+                metadata.is_file(),
+                metadata.size(),
+            );
+            self.add_entry(Entry {
+                size: metadata.size(),
+                root: root.clone(),
+                subdir,
+                subpath,
+                links: Vec::new(),
+                symlink,
+            });
+        }
+        // A file can only move with every link to it, all within one subdir
+        let mut unmovable = HashSet::new();
+        let mut inodes = inodes.into_values().collect::<Vec<_>>();
+        inodes.sort_unstable();
+        for (index, nlink) in inodes {
+            let entry = &self.entries[index];
+            let found = entry.links.len() as u64 + 1;
+            let across = entry
+                .links
+                .iter()
+                .find(|link| !link.starts_with(&entry.subdir));
+            let across = match across {
+                Some(link) if found >= nlink => Some(
+                    self.scan
+                        .grouping
+                        .split(self.roots.keys(), link, false, &mut markers)
+                        .0,
+                ),
+                _ => None,
+            };
+            if found >= nlink && across.is_none() {
+                continue;
+            }
+            for path in entry.paths() {
+                let reason = match &across {
+                    Some(subdir) => SkipReason::LinkedAcross(subdir.clone()),
+                    None => SkipReason::LinkedOutside {
+                        found,
+                        links: nlink,
+                    },
+                };
+                skip(&root.join(path), reason);
+            }
+            unmovable.insert(index);
+        }
+        self.remove_entries(&unmovable);
+        let scan = Arc::make_mut(&mut self.scan);
+        let skipped_paths = skipped.iter().map(|skipped| skipped.path.clone());
+        for path in excluded.into_iter().chain(left).chain(skipped_paths) {
+            occupied.extend(path.parent().map(Path::to_path_buf));
+            // Walked from the root, so always within it
+            let relative = path.strip_prefix(&root).unwrap_or(&path).to_path_buf();
+            if !relative.as_os_str().is_empty() {
+                scan.excluded.insert((root.clone(), relative));
+            }
+        }
+        for dir in dirs {
+            let relative = dir.strip_prefix(&root).unwrap_or(&dir).to_path_buf();
+            if !occupied.contains(&dir)
+                && !scan.excluded.contains(&(root.clone(), relative.clone()))
+            {
+                debug!("{} is empty", dir.display());
+                scan.empty_dirs.insert((root.clone(), relative));
+            }
+        }
+        if let Grouping::Marker(_) = self.scan.grouping {
+            // Markers within this root also apply to files on the others
+            self.regroup();
+        }
+        Ok(skipped)
+    }
+}
@@ -1,17 +1,19 @@
 use std::{
     collections::{HashMap, HashSet},
-    ops::AddAssign,
-    os::unix::prelude::MetadataExt,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use log::{debug, error, info, trace};
-use pathfinding::prelude::idastar;
-use walkdir::WalkDir;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     filesystem::FileSystem,
-    state::{ExistingSuccessors, LazySuccessors},
+    plan::serde_path,
+    state::{
+        Decompose, ExistingSuccessors, IdaStar, LazySuccessors, Objective, Planner, Scan,
+        SymlinkPolicy,
+    },
 };
 
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
@@ -19,20 +21,80 @@ pub struct State {
     pub(crate) roots: HashMap<std::path::PathBuf, FileSystem>,
     pub(crate) entries: Vec<Entry>,
     pub(crate) usage: HashMap<PathBuf, HashMap<PathBuf, u64>>,
+    pub(crate) objective: Objective,
+    pub(crate) scan: Arc<Scan>,
 }
 
-#[derive(Default, Debug, PartialEq, Eq, Clone)]
+#[derive(Default, Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Entry {
     pub(crate) size: u64,
     pub(crate) root: PathBuf,
     pub(crate) subdir: PathBuf,
     pub(crate) subpath: PathBuf,
+    /// Further hard links to the same file, relative to the root.
+    pub(crate) links: Vec<PathBuf>,
+    /// Contents of the file, if it is a symbolic link.
+    pub(crate) symlink: Option<PathBuf>,
 }
 
-#[derive(Default, Debug, PartialEq, Eq, Clone)]
+#[derive(Default, Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct Move {
+    #[serde(with = "serde_path")]
     pub source: PathBuf,
+    #[serde(with = "serde_path")]
     pub target: PathBuf,
+    /// The target of an earlier move of the same file, which `target` is made a
+    /// hard link of, in place of moving the data again.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_path::option"
+    )]
+    pub hard_link: Option<PathBuf>,
+    /// Contents for `target`, a symbolic link rewritten to point into its new root.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_path::option"
+    )]
+    pub symlink: Option<PathBuf>,
+    /// Whether `source` is a directory, left empty by the moves before it: `target` is
+    /// made like it if missing, then `source` removed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub directory: bool,
+}
+
+impl Entry {
+    /// Path relative to the root.
+    pub(crate) fn path(&self) -> PathBuf {
+        self.subdir.join(&self.subpath)
+    }
+
+    /// Path of this file, then of any further links to it, relative to the root.
+    pub(crate) fn paths(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        std::iter::once(self.path()).chain(self.links.iter().cloned())
+    }
+
+    /// Moves relocating this file, and any further links to it, to `other_root`.
+    pub(crate) fn moves(&self, other_root: &Path, symlinks: SymlinkPolicy) -> Vec<Move> {
+        let target = other_root.join(self.path());
+        let links = self.links.iter().map(|link| Move {
+            source: self.root.join(link),
+            target: other_root.join(link),
+            hard_link: Some(target.clone()),
+            symlink: None,
+            directory: false,
+        });
+        std::iter::once(Move {
+            source: self.root.join(self.path()),
+            target: target.clone(),
+            hard_link: None,
+            symlink: self.retarget(other_root, symlinks),
+            directory: false,
+        })
+        .chain(links)
+        .collect()
+    }
 }
 
 impl State {
@@ -44,53 +106,53 @@ impl State {
 }
 
 impl State {
+    /// Relocate with the default planner, [`IdaStar`] for each subdir in turn.
     pub fn relocate(&self) -> Option<(Vec<Move>, u64)> {
+        self.relocate_with(&Decompose(IdaStar::default()))
+    }
+
+    /// Relocate with `planner`, or by merging where all roots share a filesystem.
+    pub fn relocate_with(&self, planner: &dyn Planner) -> Option<(Vec<Move>, u64)> {
+        let (moves, cost, _) = self.relocate_bounded_with(planner)?;
+        Some((moves, cost))
+    }
+
+    /// As [`State::relocate_with`], with the least cost any relocation could have, where
+    /// the planner proves one.
+    pub fn relocate_bounded_with(
+        &self,
+        planner: &dyn Planner,
+    ) -> Option<(Vec<Move>, u64, Option<u64>)> {
         info!("{} files total", self.entries.len());
-        let r = idastar(self, |s| s.successors(), |s| s.heuristic(), |s| s.success());
-        if r.is_none() {
-            error!("No complete relocation found. Possibly try each subdir in turn.");
-            return None;
-        }
-        let (states, cost) = r.unwrap();
-        if states.len() == 1 {
-            info!("Already fully relocated");
-            return None;
+        if self.single_filesystem() {
+            info!("Every root is on one device, so merging by renames rather than planning");
+            let (mut moves, cost) = self.merge()?;
+            self.settle_symlinks(&mut moves);
+            return Some((moves, cost, None));
         }
-        let moves = Self::calculate_moves(&states);
-        info!(
-            "Relocation solution found, with {} moves, costing {}: {:?}",
-            moves.len(),
-            cost,
-            moves
-        );
-        Some((moves, cost))
+        let (mut moves, cost, lower_bound) = planner.relocate_bounded(self)?;
+        self.settle_symlinks(&mut moves);
+        Some((moves, cost, lower_bound))
     }
 
-    fn calculate_moves(states: &[State]) -> Vec<Move> {
+    /// The moves between each state of a path and the next, each differing by one entry's root.
+    pub fn calculate_moves(states: &[State]) -> Vec<Move> {
         let it1 = states.iter().skip(1);
         states
             .iter()
             .zip(it1)
-            .map(|(a, b)| {
-                let src = a
-                    .entries
-                    .iter()
-                    .map(|e| e.root.join(&e.subdir).join(&e.subpath))
-                    .collect::<HashSet<_>>();
-                let tgt = b
-                    .entries
-                    .iter()
-                    .map(|e| e.root.join(&e.subdir).join(&e.subpath))
-                    .collect::<HashSet<_>>();
-                let only_src = src.difference(&tgt).next().unwrap();
-                let only_tgt = tgt.difference(&src).next().unwrap();
-                Move {
-                    source: only_src.clone(),
-                    target: only_tgt.clone(),
-                }
-            })
+            .flat_map(|(a, b)| Self::step_moves(a, b))
             .collect::<Vec<_>>()
     }
+
+    /// The moves from state `a` to `b`, which differ by one entry's root.
+    pub(crate) fn step_moves(a: &State, b: &State) -> Vec<Move> {
+        let src = a.entries.iter().collect::<HashSet<_>>();
+        let tgt = b.entries.iter().collect::<HashSet<_>>();
+        let only_src = src.difference(&tgt).next().unwrap();
+        let only_tgt = tgt.difference(&src).next().unwrap();
+        only_src.moves(&only_tgt.root, a.scan.symlinks)
+    }
 }
 
 impl State {
@@ -100,6 +162,8 @@ impl State {
         roots: &HashMap<PathBuf, FileSystem>,
         (other_root, other_filesystem): (&PathBuf, &FileSystem),
         usage: &HashMap<PathBuf, HashMap<PathBuf, u64>>,
+        objective: &Objective,
+        scan: &Arc<Scan>,
     ) -> State {
         let mut entries = entries
             .iter()
@@ -121,13 +185,16 @@ impl State {
             // freeing of blocks
             let freed_blocks = fs.blocks(entry.size);
             debug!("freed {} blocks from {:?}", freed_blocks, entry.root);
-            fs.blocks_available = freed_blocks;
+            fs.blocks_available += freed_blocks;
+            // One inode, however many links the file has
+            fs.inodes_available += 1;
         });
         roots.entry(other_root.to_path_buf()).and_modify(|fs| {
             // consumption of blocks
             let consumed_blocks = fs.blocks(entry.size);
             debug!("consumed {} blocks from {:?}", consumed_blocks, other_root);
             fs.blocks_available -= consumed_blocks;
+            fs.inodes_available = fs.inodes_available.saturating_sub(1);
         });
         debug!("new roots: {roots:?}");
         // Modify usage
@@ -151,10 +218,13 @@ impl State {
             entries,
             roots,
             usage,
+            objective: objective.to_owned(),
+            scan: Arc::clone(scan),
         }
     }
 
-    fn successors(&self) -> Box<dyn Iterator<Item = (State, u64)>> {
+    /// Each state reachable by moving one entry to another root, with the bytes moved.
+    pub fn successors(&self) -> Box<dyn Iterator<Item = (State, u64)>> {
         if self.entries.len() > 1 {
             let successors = LazySuccessors::from(self);
             info!("successors: {successors:?}");
@@ -175,11 +245,14 @@ impl State {
             let mut result = Vec::new();
             for entry in &self.entries {
                 for (other_root, other_fs) in &self.roots {
-                    if entry.root == *other_root {
+                    if entry.root == *other_root
+                        || other_fs.drain
+                        || !self.scan.constraints.may_receive(&entry.subdir, other_root)
+                    {
                         continue;
                     }
                     num_tests += 1;
-                    if other_fs.free_bytes() < entry.size {
+                    if !other_fs.has_room(entry.size) {
                         // No space to move into 'other_root' at this point
                         debug!(
                             "{}: No space for {:?} in {:?}",
@@ -187,6 +260,22 @@ impl State {
                         );
                         continue;
                     }
+                    let directories = self
+                        .scan
+                        .directories_made(
+                            [entry],
+                            self.entries.iter().filter(|e| e.subdir == entry.subdir),
+                            other_root,
+                            &self.usage,
+                        )
+                        .len() as u64;
+                    if !other_fs.has_inodes(1 + directories) {
+                        debug!(
+                            "{}: No inodes for {:?} in {:?}",
+                            num_tests, entry, other_root
+                        );
+                        continue;
+                    }
                     let mut new_entries = self
                         .entries
                         .iter()
@@ -211,10 +300,13 @@ impl State {
                     roots.entry(entry.root.clone()).and_modify(|fs| {
                         // Pessimistic freeing of blocks
                         fs.blocks_available += fs.blocks(entry.size);
+                        fs.inodes_available += 1;
                     });
                     roots.entry(other_root.clone()).and_modify(|fs| {
                         // Pessimistic consumption of blocks
                         fs.blocks_available -= fs.blocks(entry.size);
+                        fs.inodes_available =
+                            fs.inodes_available.saturating_sub(1 + directories);
                     });
                     // Modify usage
                     let mut usage = self.usage.clone();
@@ -233,6 +325,8 @@ impl State {
                         entries: new_entries,
                         roots,
                         usage,
+                        objective: self.objective.clone(),
+                        scan: Arc::clone(&self.scan),
                     };
                     result.push((new_state, cost));
                 }
@@ -245,7 +339,8 @@ impl State {
         }
     }
 
-    fn heuristic(&self) -> u64 {
+    /// Lower bound on the bytes still to move to reach success.
+    pub fn heuristic(&self) -> u64 {
         let mut total = 0;
         for subdir in self.usage.keys() {
             let v = self.entries.iter().filter(|e| e.subdir == *subdir).fold(
@@ -255,165 +350,239 @@ impl State {
                     acc
                 },
             );
-            if v.is_empty() {
-                info!("entries: {:?}", self.entries);
-                info!("usage: {:?}", self.usage);
-                info!("subpath {:?}, values = {:?}", subdir, v);
-            }
             // Total size of all files within this subpath (over all roots)
             let subpath_total: u64 = v.values().sum();
-            // Minimum cost of moving all files to each root (total within that root, less the overall total)
-            let min_cost = v.values().map(|v| subpath_total - *v).min().unwrap();
+            // Minimum cost of moving all files to each root they may end up on (total within that root, less the overall total)
+            let min_cost = self
+                .roots
+                .iter()
+                .filter(|(_, fs)| fs.keeps_files())
+                .filter(|(root, _)| {
+                    let constraints = &self.scan.constraints;
+                    constraints.may_receive(subdir, root)
+                        || constraints.may_hold(subdir, root) && v.get(root) == Some(&subpath_total)
+                })
+                .map(|(root, _)| subpath_total - v.get(root).copied().unwrap_or_default())
+                .min()
+                .unwrap_or(subpath_total);
             total += min_cost;
         }
-        total
+        total.max(self.objective_heuristic())
     }
 
-    fn success(&self) -> bool {
-        // TODO Adjust so scratchpad roots are empty
+    /// Whether every subdir is on a single root it may end on, and the objective is met.
+    pub fn success(&self) -> bool {
+        // Scratchpad roots are only for staging, and drained roots are being retired, so must end empty
+        if self
+            .entries
+            .iter()
+            .any(|e| !self.roots[&e.root].keeps_files())
+        {
+            return false;
+        }
         !self
             .usage
             .iter()
             .any(|(_subpath, roots)| roots.values().filter(|v| **v != 0).count() > 1)
+            && self.usage.iter().all(|(subdir, roots)| {
+                roots
+                    .iter()
+                    .filter(|(_, v)| **v != 0)
+                    .all(|(root, _)| self.scan.constraints.may_hold(subdir, root))
+            })
+            && self.objective_met()
     }
 }
 
-impl AddAssign<&str> for State {
-    fn add_assign(&mut self, rhs: &str) {
-        self.scan(rhs, false);
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::{
+        filesystem::Reserve,
+        state::{fixture, Objective},
+        Constraints, Move,
+    };
+
+    #[test]
+    fn scratch_must_end_empty() {
+        let mut state = fixture(&[("a", 10), ("s", 10)], &[("s", "A", "1", 10)]);
+        state.roots.get_mut(&PathBuf::from("s")).unwrap().scratch = true;
+        assert!(!state.success());
+        assert_eq!(10, state.heuristic());
     }
-}
 
-impl AddAssign<String> for State {
-    fn add_assign(&mut self, rhs: String) {
-        self.scan(&rhs, false);
+    #[test]
+    fn drain_root() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 10), ("d", 10)],
+            &[
+                ("d", "A", "1", 2 * 4096),
+                ("a", "A", "2", 10),
+                ("d", "B", "3", 10),
+                ("b", "B", "4", 10),
+            ],
+        );
+        let d = state.roots.get_mut(&PathBuf::from("d")).unwrap();
+        *d = d.clone().with_drain(true);
+        assert!(!state.success());
+        let (moves, cost) = state.relocate().unwrap();
+        // Even though A would be cheaper to gather onto d
+        assert_eq!(2 * 4096 + 10, cost);
+        assert_eq!(2, moves.len());
+        assert!(moves.contains(&Move {
+            source: PathBuf::from("d/A/1"),
+            target: PathBuf::from("a/A/1"),
+            ..Default::default()
+        }));
+        assert!(moves.contains(&Move {
+            source: PathBuf::from("d/B/3"),
+            target: PathBuf::from("b/B/3"),
+            ..Default::default()
+        }));
     }
-}
 
-impl AddAssign<&String> for State {
-    fn add_assign(&mut self, rhs: &String) {
-        self.scan(rhs, false);
+    #[test]
+    fn stage_through_scratch() {
+        // Both roots full: the only way to consolidate is via the scratch root
+        let mut state = fixture(
+            &[("a", 0), ("b", 0), ("s", 10)],
+            &[
+                ("a", "A", "1", 10),
+                ("b", "A", "2", 10),
+                ("a", "B", "3", 10),
+                ("b", "B", "4", 10),
+            ],
+        );
+        state.roots.get_mut(&PathBuf::from("s")).unwrap().scratch = true;
+        let (moves, cost) = state.relocate().unwrap();
+        assert_eq!(30, cost);
+        assert_eq!(3, moves.len());
+        assert!(moves
+            .iter()
+            .any(|m| m.target.starts_with("s") && !m.source.starts_with("s")));
+        assert!(moves.iter().any(|m| m.source.starts_with("s")));
     }
-}
 
-impl State {
-    fn add_entry(&mut self, root: PathBuf, subdir: PathBuf, subpath: PathBuf, size: u64) {
-        *self
-            .usage
-            .entry(subdir.clone())
-            .or_default()
-            .entry(root.clone())
-            .or_default() += 1;
-        let entry = Entry {
-            root,
-            subdir,
-            subpath,
-            size,
-        };
-        self.entries.push(entry);
+    #[test]
+    fn inodes_exhausted() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 10)],
+            &[("a", "A", "1", 10), ("b", "A", "2", 3 * 4096)],
+        );
+        // b has blocks to spare, but only its last inode, so the larger file must move
+        let b = state.roots.get_mut(&PathBuf::from("b")).unwrap();
+        *b = b.clone().with_inodes(1, 1000);
+        let (moves, cost) = state.relocate().unwrap();
+        assert_eq!(3 * 4096, cost);
+        assert_eq!(
+            vec![Move {
+                source: PathBuf::from("b/A/2"),
+                target: PathBuf::from("a/A/2"),
+                ..Default::default()
+            }],
+            moves
+        );
     }
 
-    fn scan(&mut self, root: &str, is_scratchpad: bool) {
-        let cur_dir = match std::env::current_dir() {
-            Ok(d) => d,
-            Err(e) => {
-                error!("Error scanning {:?} getting current directory: {}", root, e);
-                return;
-            }
-        };
-        let root = match cur_dir.join(root).canonicalize() {
-            Ok(r) => r,
-            Err(e) => {
-                error!("Error scanning {:?} from {:?}: {}", root, cur_dir, e);
-                return;
-            }
+    #[test]
+    fn placement_constraints() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 10), ("c", 10)],
+            &[("a", "A", "1", 10), ("b", "A", "2", 3 * 4096)],
+        );
+        let mut relocate = |constraints: Constraints| {
+            std::sync::Arc::make_mut(&mut state.scan).constraints = constraints;
+            state.relocate().unwrap()
         };
-        if self.roots.contains_key(&root) {
-            error!("Skipping duplicate scan root: {root:?}");
-            return;
-        }
-        let root_dev_id = root.metadata().unwrap().dev();
-        self.roots.insert(
-            root.clone(),
-            FileSystem::from((root.as_path(), is_scratchpad)),
+        // Unconstrained, the smaller file moves
+        let (moves, cost) = relocate(Constraints::default());
+        assert_eq!(10, cost);
+        assert_eq!(PathBuf::from("b/A/1"), moves[0].target);
+
+        let (moves, cost) = relocate(Constraints::default().pin("A", "a"));
+        assert_eq!(3 * 4096, cost);
+        assert_eq!(PathBuf::from("a/A/2"), moves[0].target);
+
+        let (moves, cost) = relocate(Constraints::default().forbid("A", "b"));
+        assert_eq!(3 * 4096, cost);
+        assert_eq!(PathBuf::from("a/A/2"), moves[0].target);
+
+        // Neither may receive anything, so both move to c
+        let (moves, cost) = relocate(Constraints::default().no_target("a").no_target("b"));
+        assert_eq!(3 * 4096 + 10, cost);
+        assert!(moves.iter().all(|m| m.target.starts_with("c")));
+    }
+
+    #[test]
+    fn reserve_left_free() {
+        let mut state = fixture(
+            &[("a", 20), ("b", 10)],
+            &[("a", "A", "1", 10), ("b", "A", "2", 3 * 4096)],
         );
-        info!(
-            "scan {:?} from {}",
-            root,
-            std::env::current_dir().unwrap().display()
+        // b would fall below its reserve, so the larger file must move
+        state.set_reserve(Reserve::BasisPoints(1000));
+        let (moves, cost) = state.relocate().unwrap();
+        assert_eq!(3 * 4096, cost);
+        assert_eq!(
+            vec![Move {
+                source: PathBuf::from("b/A/2"),
+                target: PathBuf::from("a/A/2"),
+                ..Default::default()
+            }],
+            moves
         );
-        let walker = WalkDir::new(&root).same_file_system(true).into_iter();
-        for entry in walker.flatten() {
-            let metadata = entry.metadata().unwrap();
-            let dev_id = metadata.dev();
-
-            trace!(
-                "{:?} {} {:o} {:?} {} {} (@ {})",
-                dev_id,
-                entry.path().display(),
-                metadata.mode(),
-                metadata.is_dir(),
-                metadata.is_file(),
-                metadata.size(),
-                std::env::current_dir()
-                    .unwrap()
-                    .as_path()
-                    .join(entry.path())
-                    .canonicalize()
-                    .map_or("[missing]".to_string(), |p| p.display().to_string())
-            );
+    }
 
-            if dev_id != root_dev_id {
-                error!(
-                    "skipping {}: not on same device as origin root {:?}",
-                    entry.path().display(),
-                    root.clone()
-                );
-                continue;
-            }
+    #[test]
+    fn rebalance_free_space() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 90)],
+            &[("a", "A", "1", 39 * 4096), ("a", "B", "2", 10)],
+        );
+        // Already consolidated, 80% apart
+        assert!(state.success());
+        state.set_objective(Objective::Rebalance { tolerance: 1000 });
+        assert!(!state.success());
+        assert!(state.heuristic() <= 39 * 4096);
 
-            if !metadata.is_file() {
-                debug!("skipping {}: not a file", entry.path().display());
-                continue;
-            }
-            let c = entry
-                .path()
-                .strip_prefix(&root)
-                .unwrap()
-                .components()
-                .next()
-                .unwrap();
-            let subdir = if entry
-                .path()
-                .strip_prefix(root.join(c))
-                .unwrap()
-                .iter()
-                .count()
-                == 0
-            {
-                PathBuf::new()
-            } else {
-                PathBuf::from(c.as_os_str())
-            };
-            let subpath = entry.path().strip_prefix(root.join(&subdir)).unwrap();
-            let subpath = subpath.to_path_buf();
-            debug!(
-                "{:?} {:?} {:?} {:?} {:o} {:?} {} {} (@ {})",
-                dev_id,
-                root,
-                subdir,
-                subpath,
-                metadata.mode(),
-                metadata.is_dir(),
-                metadata.is_file(),
-                metadata.size(),
-                cur_dir
-                    .join(entry.path())
-                    .canonicalize()
-                    .map_or("[missing]".to_string(), |p| p.display().to_string())
-            );
-            let size = metadata.size();
-            self.add_entry(root.clone(), subdir, subpath, size);
-        }
+        let (moves, cost) = state.relocate().unwrap();
+        assert_eq!(39 * 4096, cost);
+        assert_eq!(
+            vec![Move {
+                source: PathBuf::from("a/A/1"),
+                target: PathBuf::from("b/A/1"),
+                ..Default::default()
+            }],
+            moves
+        );
+    }
+
+    #[test]
+    fn hard_links_move_together() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 10)],
+            &[("a", "A", "1", 4096), ("b", "A", "2", 8192)],
+        );
+        state.entries[0].links.push(PathBuf::from("A/1.link"));
+        let (moves, cost) = state.relocate().unwrap();
+        // Charged once, then linked on the target
+        assert_eq!(4096, cost);
+        assert_eq!(
+            vec![
+                Move {
+                    source: PathBuf::from("a/A/1"),
+                    target: PathBuf::from("b/A/1"),
+                    ..Default::default()
+                },
+                Move {
+                    source: PathBuf::from("a/A/1.link"),
+                    target: PathBuf::from("b/A/1.link"),
+                    hard_link: Some(PathBuf::from("b/A/1")),
+                    ..Default::default()
+                },
+            ],
+            moves
+        );
     }
 }
@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::{plan::innermost_root, Entry, Move, State};
+
+/// What becomes of symbolic links moved to another root.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Move links unchanged, wherever they point.
+    #[default]
+    Preserve,
+    /// Rewrite absolute links pointing into their old root, to point into the new one,
+    /// where what they point to moves there too.
+    Rewrite,
+}
+
+impl Entry {
+    /// The contents for this symbolic link once moved to `other_root`, if they must change.
+    pub(crate) fn retarget(&self, other_root: &Path, policy: SymlinkPolicy) -> Option<PathBuf> {
+        let contents = self.symlink.as_ref()?;
+        match policy {
+            SymlinkPolicy::Preserve => None,
+            SymlinkPolicy::Rewrite => {
+                let within = contents.strip_prefix(&self.root).ok()?;
+                (other_root != self.root).then(|| other_root.join(within))
+            }
+        }
+    }
+}
+
+impl State {
+    /// Leave unchanged each link `moves` rewrite to point into its new root, unless the
+    /// subdir it points into ends wholly on that root too, once the moves are made.
+    pub(crate) fn settle_symlinks(&self, moves: &mut [Move]) {
+        if !moves.iter().any(|m| m.symlink.is_some()) {
+            return;
+        }
+        let roots_after = self.roots_after(moves);
+        for m in moves.iter_mut() {
+            let Some(contents) = &m.symlink else {
+                continue;
+            };
+            let Some(root) = innermost_root(self.roots.keys(), &m.target) else {
+                continue;
+            };
+            let Ok(within) = contents.strip_prefix(root) else {
+                continue;
+            };
+            let follows = within
+                .ancestors()
+                .find_map(|subdir| roots_after.get(subdir))
+                .is_some_and(|roots| roots.len() == 1 && roots.contains(root.as_path()));
+            if !follows {
+                debug!("{:?} left as it was: what it points to stays put", m.source);
+                m.symlink = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use super::SymlinkPolicy;
+    use crate::{state::fixture, Entry};
+
+    #[test]
+    fn rewrites_absolute_links_into_root() {
+        let link = |contents: &str| Entry {
+            root: PathBuf::from("/a"),
+            subdir: PathBuf::from("c"),
+            subpath: PathBuf::from("link"),
+            symlink: Some(PathBuf::from(contents)),
+            ..Default::default()
+        };
+        let b = Path::new("/b");
+        assert_eq!(
+            Some(PathBuf::from("/b/c/1.txt")),
+            link("/a/c/1.txt").retarget(b, SymlinkPolicy::Rewrite)
+        );
+        assert_eq!(
+            None,
+            link("/a/c/1.txt").retarget(b, SymlinkPolicy::Preserve)
+        );
+        assert_eq!(None, link("1.txt").retarget(b, SymlinkPolicy::Rewrite));
+        assert_eq!(None, link("/ab/1.txt").retarget(b, SymlinkPolicy::Rewrite));
+        assert_eq!(None, link("/etc/hosts").retarget(b, SymlinkPolicy::Rewrite));
+    }
+
+    #[test]
+    fn rewrites_only_links_into_moving_subdirs() {
+        let mut state = fixture(
+            &[("a", 10), ("b", 10)],
+            &[
+                ("a", "X", "into_y", 1),
+                ("a", "X", "into_x", 1),
+                ("a", "X", "1", 1),
+                ("b", "X", "2", 30),
+                ("a", "Y", "3", 10),
+            ],
+        );
+        for entry in &mut state.entries {
+            entry.symlink = match entry.subpath.to_str() {
+                Some("into_y") => Some(PathBuf::from("a/Y/3")),
+                Some("into_x") => Some(PathBuf::from("a/X/1")),
+                _ => None,
+            };
+        }
+        Arc::make_mut(&mut state.scan).symlinks = SymlinkPolicy::Rewrite;
+        let plan = state.plan().unwrap();
+        let contents = |source: &str| {
+            plan.moves
+                .iter()
+                .find(|m| m.source == Path::new(source))
+                .map(|m| m.symlink.clone())
+        };
+        // Y stays on a, so the link into it must still point there
+        assert_eq!(Some(None), contents("a/X/into_y"));
+        assert_eq!(Some(Some(PathBuf::from("b/X/1"))), contents("a/X/into_x"));
+    }
+}
@@ -1,6 +1,17 @@
-use std::{fs, io, path::PathBuf};
+use std::{
+    ffi::OsStr,
+    fs, io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::PathBuf,
+};
 
-use relocation::{Move, State};
+use relocation::{
+    Constraints, Executor, FileSystem, Filter, Grouping, HashAlgorithm, Journal, Mismatch, Move,
+    Plan, ScanError, SkipReason, Stage, State, SymlinkPolicy, DEFAULT_IGNORE_FILE, DEFAULT_MARKER,
+};
 use walkdir::WalkDir;
 
 fn setup(test_dir: &str, files: &[(&str, &str)]) -> io::Result<()> {
@@ -85,19 +96,926 @@ fn two_dirs() -> io::Result<()> {
     assert!(r.is_some());
     let (moves, cost) = r.unwrap();
     println!("{cost}: {moves:?}");
-    assert_eq!(14, cost);
+    // Same filesystem, so merged by renames
+    assert_eq!(2, cost);
     assert_eq!(2, moves.len());
 
     let full_test_dir = PathBuf::from(test_dir).canonicalize().unwrap();
     assert!(moves.contains(&Move {
         source: full_test_dir.join("a/c/1.txt"),
-        target: full_test_dir.join("b/c/1.txt")
+        target: full_test_dir.join("b/c/1.txt"),
+        ..Default::default()
     }));
     assert!(moves.contains(&Move {
         source: full_test_dir.join("a/c/5.txt"),
-        target: full_test_dir.join("b/c/5.txt")
+        target: full_test_dir.join("b/c/5.txt"),
+        ..Default::default()
     }));
 
     cleanup(test_dir)?;
     Ok(())
 }
+
+#[test]
+fn two_dirs_execute() -> io::Result<()> {
+    let test_dir = "test_dir_two_dirs_execute";
+
+    setup(
+        test_dir,
+        &[
+            ("b/c/3.txt", "3"),
+            ("b/c/2.txt", "hello"),
+            ("b/c/4.txt", "1234567890"),
+            ("a/c/1.txt", "hello_world"),
+            ("a/c/d/5.txt", "cat"),
+        ],
+    )?;
+
+    let mut state = State::default();
+    state += test_dir.to_string() + "/a";
+    state += test_dir.to_string() + "/b";
+
+    let (moves, _cost) = state.relocate().unwrap();
+    let report = Executor::default().execute(&moves)?;
+    assert!(report.failures.is_empty(), "{report:?}");
+
+    dump(test_dir);
+
+    let test_dir_path = PathBuf::from(test_dir);
+    assert!(!test_dir_path.join("a/c/1.txt").exists());
+    assert!(!test_dir_path.join("a/c/d/5.txt").exists());
+    assert_eq!(
+        "hello_world",
+        fs::read_to_string(test_dir_path.join("b/c/1.txt"))?
+    );
+    assert_eq!(
+        "cat",
+        fs::read_to_string(test_dir_path.join("b/c/d/5.txt"))?
+    );
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn execute_refuses_to_overwrite() -> io::Result<()> {
+    let test_dir = "test_dir_execute_refuses_to_overwrite";
+
+    setup(
+        test_dir,
+        &[("a/c/1.txt", "source"), ("b/c/1.txt", "target")],
+    )?;
+
+    let full_test_dir = PathBuf::from(test_dir).canonicalize()?;
+    let report = Executor::default().execute(&[Move {
+        source: full_test_dir.join("a/c/1.txt"),
+        target: full_test_dir.join("b/c/1.txt"),
+        ..Default::default()
+    }])?;
+    assert_eq!(1, report.failures.len());
+    assert_eq!(
+        "source",
+        fs::read_to_string(full_test_dir.join("a/c/1.txt"))?
+    );
+    assert_eq!(
+        "target",
+        fs::read_to_string(full_test_dir.join("b/c/1.txt"))?
+    );
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn resume_journal() -> io::Result<()> {
+    let test_dir = "test_dir_resume_journal";
+
+    setup(
+        test_dir,
+        &[
+            ("a/c/1.txt", "one"),
+            ("a/c/2.txt", "two"),
+            ("a/c/3.txt", "three"),
+            ("b/c/3.txt", "partial"),
+        ],
+    )?;
+
+    let full_test_dir = PathBuf::from(test_dir).canonicalize()?;
+    let moves = ["1.txt", "2.txt", "3.txt"]
+        .iter()
+        .map(|name| Move {
+            source: full_test_dir.join("a/c").join(name),
+            target: full_test_dir.join("b/c").join(name),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    // Interrupted after the first move, part way through copying the third
+    let journal_path = full_test_dir.join("journal");
+    let mut journal = Journal::create(&journal_path, &moves)?;
+    fs::rename(&moves[0].source, &moves[0].target)?;
+    journal.record(0, Stage::SourceRemoved)?;
+    journal.record(2, Stage::Copying)?;
+    drop(journal);
+    fs::write(&moves[0].source, "recreated")?;
+
+    let report = Executor::default().resume(&journal_path)?;
+    assert!(report.failures.is_empty(), "{report:?}");
+
+    // Completed moves are not redone
+    assert_eq!("recreated", fs::read_to_string(&moves[0].source)?);
+    assert_eq!("one", fs::read_to_string(&moves[0].target)?);
+    assert!(!moves[1].source.exists());
+    assert_eq!("two", fs::read_to_string(&moves[1].target)?);
+    assert!(!moves[2].source.exists());
+    assert_eq!("three", fs::read_to_string(&moves[2].target)?);
+
+    let (_journal, records) = Journal::open(&journal_path)?;
+    assert!(records
+        .iter()
+        .all(|(_, stage)| *stage == Stage::SourceRemoved));
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn verify_rejects_corrupt_copy() -> io::Result<()> {
+    let test_dir = "test_dir_verify_rejects_corrupt_copy";
+
+    setup(
+        test_dir,
+        &[
+            ("a/c/1.txt", "hello"),
+            ("b/c/1.txt", "jello"),
+            ("a/c/2.txt", "world"),
+            ("b/c/2.txt", "world"),
+        ],
+    )?;
+
+    let full_test_dir = PathBuf::from(test_dir).canonicalize()?;
+    let moves = ["1.txt", "2.txt"]
+        .iter()
+        .map(|name| Move {
+            source: full_test_dir.join("a/c").join(name),
+            target: full_test_dir.join("b/c").join(name),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    // Interrupted after copying both files, with the first copy corrupted
+    let journal_path = full_test_dir.join("journal");
+    let mut journal = Journal::create(&journal_path, &moves)?;
+    for index in 0..moves.len() {
+        journal.record(index, Stage::Copying)?;
+        journal.record(index, Stage::Copied)?;
+    }
+    drop(journal);
+
+    for algorithm in [HashAlgorithm::Crc32, HashAlgorithm::Sha256] {
+        let report = Executor::default()
+            .verify(algorithm)
+            .resume(&journal_path)?;
+        assert_eq!(1, report.failures.len());
+        assert_eq!(moves[0], report.failures[0].failed);
+        assert_eq!("hello", fs::read_to_string(&moves[0].source)?);
+    }
+    assert!(!moves[1].source.exists());
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn execute_creates_directories_like_source() -> io::Result<()> {
+    let test_dir = "test_dir_execute_creates_directories_like_source";
+
+    setup(
+        test_dir,
+        &[
+            ("b/c/3.txt", "3"),
+            ("b/c/2.txt", "hello"),
+            ("a/c/d/e/1.txt", "1"),
+        ],
+    )?;
+
+    let test_dir_path = PathBuf::from(test_dir);
+    fs::set_permissions(
+        test_dir_path.join("a/c/d"),
+        fs::Permissions::from_mode(0o750),
+    )?;
+    // Give the source directory a distinctive timestamp
+    let reference = fs::metadata("Cargo.toml")?;
+    fs::File::open(test_dir_path.join("a/c/d"))?.set_modified(reference.modified()?)?;
+    let source_metadata = fs::metadata(test_dir_path.join("a/c/d"))?;
+
+    let full_test_dir = test_dir_path.canonicalize()?;
+    let report = Executor::default().execute(&[Move {
+        source: full_test_dir.join("a/c/d/e/1.txt"),
+        target: full_test_dir.join("b/c/d/e/1.txt"),
+        ..Default::default()
+    }])?;
+    assert!(report.failures.is_empty(), "{report:?}");
+    assert!(report.unpreserved.is_empty(), "{report:?}");
+
+    let target_metadata = fs::metadata(test_dir_path.join("b/c/d"))?;
+    assert_eq!(0o750, target_metadata.mode() & 0o7777);
+    assert_eq!(source_metadata.uid(), target_metadata.uid());
+    assert_eq!(source_metadata.modified()?, target_metadata.modified()?);
+    assert!(test_dir_path.join("b/c/d/e/1.txt").exists());
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn plan_save_load_apply() -> io::Result<()> {
+    let test_dir = "test_dir_plan_save_load_apply";
+
+    setup(
+        test_dir,
+        &[
+            ("b/c/3.txt", "3"),
+            ("b/c/2.txt", "hello"),
+            ("b/c/4.txt", "1234567890"),
+            ("a/c/1.txt", "hello_world"),
+        ],
+    )?;
+
+    let mut state = State::default();
+    state += test_dir.to_string() + "/a";
+    state += test_dir.to_string() + "/b";
+
+    let plan = state.plan().unwrap();
+    assert_eq!(2, plan.roots.len());
+    // The file, then the directory it leaves empty
+    assert_eq!(2, plan.moves.len());
+    assert!(plan.moves[1].directory);
+    let plan_path = PathBuf::from(test_dir).join("plan.json");
+    plan.save(&plan_path)?;
+    let loaded = Plan::load(&plan_path)?;
+    assert_eq!(plan, loaded);
+    assert_eq!(Vec::<Mismatch>::new(), loaded.mismatches());
+
+    let mut moved = loaded.clone();
+    moved.roots[0].filesystem = FileSystem::new(u64::MAX, 512, 0, false);
+    assert_eq!(
+        vec![Mismatch::DifferentFilesystem(moved.roots[0].path.clone())],
+        moved.mismatches()
+    );
+
+    let report = Executor::default().execute(&loaded.moves)?;
+    assert!(report.failures.is_empty(), "{report:?}");
+    assert!(PathBuf::from(test_dir).join("b/c/1.txt").exists());
+    assert!(!PathBuf::from(test_dir).join("a/c").exists());
+    assert!(PathBuf::from(test_dir).join("a").exists());
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn plan_as_shell_script() -> io::Result<()> {
+    let test_dir = "test_dir_plan_as_shell_script";
+
+    setup(
+        test_dir,
+        &[
+            ("b/c/3.txt", "3"),
+            ("b/c/2.txt", "hello"),
+            ("b/c/4.txt", "1234567890"),
+            ("a/c/it's $HOME.txt", "quoted"),
+        ],
+    )?;
+    let awkward = OsStr::from_bytes(b"-\xff\n.txt");
+    fs::write(PathBuf::from(test_dir).join("a/c").join(awkward), "x")?;
+
+    let mut state = State::default();
+    state += test_dir.to_string() + "/a";
+    state += test_dir.to_string() + "/b";
+
+    let plan = state.plan().unwrap();
+    assert_eq!(3, plan.moves.len());
+    let script_path = PathBuf::from(test_dir).join("plan.sh");
+    plan.write_script(fs::File::create(&script_path)?)?;
+    // Only POSIX options, so no copying of ownership and mode by reference
+    assert!(!fs::read(&script_path)?
+        .windows(b"--reference".len())
+        .any(|window| window == b"--reference"));
+
+    let status = std::process::Command::new("sh")
+        .arg(&script_path)
+        .status()?;
+    assert!(status.success());
+
+    let test_dir_path = PathBuf::from(test_dir);
+    assert!(!test_dir_path.join("a/c/it's $HOME.txt").exists());
+    assert_eq!(
+        "quoted",
+        fs::read_to_string(test_dir_path.join("b/c/it's $HOME.txt"))?
+    );
+    assert!(!test_dir_path.join("a/c").join(awkward).exists());
+    assert_eq!(
+        "x",
+        fs::read_to_string(test_dir_path.join("b/c").join(awkward))?
+    );
+    assert!(!test_dir_path.join("a/c").exists());
+
+    // Moves already made are refused rather than clobbered
+    let status = std::process::Command::new("sh")
+        .arg(&script_path)
+        .status()?;
+    assert!(!status.success());
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn merge_same_filesystem() -> io::Result<()> {
+    let test_dir = "test_dir_merge_same_filesystem";
+
+    setup(
+        test_dir,
+        &[
+            ("a/c/1.txt", "1"),
+            ("a/c/d/2.txt", "2"),
+            ("a/c/d/3.txt", "3"),
+            ("a/c/e/4.txt", "4"),
+            ("a/f/5.txt", "5"),
+            ("b/c/e/6.txt", "6"),
+            ("b/c/7.txt", "7"),
+            ("b/c/8.txt", "8"),
+            ("b/c/9.txt", "9"),
+            ("b/c/10.txt", "10"),
+        ],
+    )?;
+
+    let mut state = State::default();
+    state += test_dir.to_string() + "/a";
+    state += test_dir.to_string() + "/b";
+
+    let (moves, cost) = state.relocate().unwrap();
+    println!("{cost}: {moves:?}");
+    // Whole directories are renamed where the target lacks them
+    let full_test_dir = PathBuf::from(test_dir).canonicalize()?;
+    assert_eq!(3, cost);
+    assert_eq!(3, moves.len());
+    for path in ["c/1.txt", "c/d", "c/e/4.txt"] {
+        assert!(moves.contains(&Move {
+            source: full_test_dir.join("a").join(path),
+            target: full_test_dir.join("b").join(path),
+            ..Default::default()
+        }));
+    }
+
+    let report = Executor::default().execute(&moves)?;
+    assert!(report.failures.is_empty(), "{report:?}");
+    assert!(full_test_dir.join("b/c/d/3.txt").exists());
+    assert!(full_test_dir.join("a/f/5.txt").exists());
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn scan_reports_errors() -> io::Result<()> {
+    let test_dir = "test_dir_scan_reports_errors";
+
+    setup(
+        test_dir,
+        &[("a/c/1.txt", "1"), ("a/d/2.txt", "2"), ("b/c/3.txt", "3")],
+    )?;
+
+    let mut state = State::default();
+    assert!(matches!(
+        state.try_add_root(&(test_dir.to_string() + "/missing")),
+        Err(ScanError::Root { .. })
+    ));
+    assert!(matches!(
+        state.try_add_root(&(test_dir.to_string() + "/a/c/1.txt")),
+        Err(ScanError::NotADirectory(_))
+    ));
+
+    let unreadable = PathBuf::from(test_dir).join("a/d");
+    fs::set_permissions(&unreadable, fs::Permissions::from_mode(0o000))?;
+    let skipped = state.try_add_root(&(test_dir.to_string() + "/a"));
+    // Permissions do not bind privileged users
+    let readable = fs::read_dir(&unreadable).is_ok();
+    fs::set_permissions(&unreadable, fs::Permissions::from_mode(0o755))?;
+    let skipped = skipped.unwrap();
+    if readable {
+        assert!(skipped.is_empty(), "{skipped:?}");
+    } else {
+        assert_eq!(1, skipped.len(), "{skipped:?}");
+        assert!(matches!(skipped[0].reason, SkipReason::Unreadable(_)));
+    }
+    assert!(matches!(
+        state.try_add_root(&(test_dir.to_string() + "/a")),
+        Err(ScanError::Duplicate(_))
+    ));
+    assert!(state.try_add_root(&(test_dir.to_string() + "/b")).is_ok());
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn group_depth() -> io::Result<()> {
+    let test_dir = "test_dir_group_depth";
+
+    setup(
+        test_dir,
+        &[
+            ("a/Movies/A/1.txt", "1"),
+            ("a/Movies/B/2.txt", "2"),
+            ("a/Movies/notes.txt", "notes"),
+            ("b/Movies/A/3.txt", "3"),
+            ("b/Movies/C/4.txt", "4"),
+        ],
+    )?;
+
+    let mut state = State::default();
+    state.set_group_depth(2);
+    state += test_dir.to_string() + "/a";
+    state += test_dir.to_string() + "/b";
+
+    let (moves, cost) = state.relocate().unwrap();
+    println!("{cost}: {moves:?}");
+    // Only the split title moves; the category itself may span roots
+    let full_test_dir = PathBuf::from(test_dir).canonicalize()?;
+    assert_eq!(1, cost);
+    assert!(
+        moves
+            == [Move {
+                source: full_test_dir.join("a/Movies/A/1.txt"),
+                target: full_test_dir.join("b/Movies/A/1.txt"),
+                ..Default::default()
+            }]
+            || moves
+                == [Move {
+                    source: full_test_dir.join("b/Movies/A/3.txt"),
+                    target: full_test_dir.join("a/Movies/A/3.txt"),
+                    ..Default::default()
+                }]
+    );
+
+    // Regrouping by category merges whole categories instead
+    state.set_group_depth(1);
+    let (moves, _) = state.relocate().unwrap();
+    println!("{moves:?}");
+    assert!(moves.len() > 1);
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn group_by_marker() -> io::Result<()> {
+    let test_dir = "test_dir_group_by_marker";
+
+    setup(
+        test_dir,
+        &[
+            ("a/lib/x/.relocation-unit", ""),
+            ("a/lib/x/1.txt", "1"),
+            ("a/lib/y/2.txt", "2"),
+            ("b/lib/x/3.txt", "3"),
+            ("b/lib/z/4.txt", "4"),
+        ],
+    )?;
+
+    let mut state = State::default();
+    state.set_grouping(Grouping::Marker(DEFAULT_MARKER.into()));
+    state += test_dir.to_string() + "/a";
+    state += test_dir.to_string() + "/b";
+
+    let (moves, cost) = state.relocate().unwrap();
+    println!("{cost}: {moves:?}");
+    // Unmarked directories fall back to grouping by their first component, "lib"
+    let full_test_dir = PathBuf::from(test_dir).canonicalize()?;
+    assert_eq!(
+        vec![
+            Move {
+                source: full_test_dir.join("b/lib/z"),
+                target: full_test_dir.join("a/lib/z"),
+                ..Default::default()
+            },
+            Move {
+                source: full_test_dir.join("b/lib/x/3.txt"),
+                target: full_test_dir.join("a/lib/x/3.txt"),
+                ..Default::default()
+            },
+        ],
+        moves
+    );
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn scan_filters() -> io::Result<()> {
+    let test_dir = "test_dir_scan_filters";
+
+    setup(
+        test_dir,
+        &[
+            ("a/.relocationignore", "*.part\n"),
+            ("a/c/1.txt", "1"),
+            ("a/c/2.part", "2"),
+            ("a/c/3.part", "3"),
+            ("a/lost+found/4.txt", "4"),
+            ("a/.Trash-1000/5.txt", "5"),
+            ("b/c/6.txt", "6"),
+            ("b/c/7.txt", "7"),
+            ("b/c/8.txt", "8"),
+        ],
+    )?;
+
+    let mut state = State::default();
+    state.set_filter(
+        Filter::default()
+            .ignore_file(DEFAULT_IGNORE_FILE)
+            .exclude("/lost+found")
+            .exclude(".Trash-*")
+            .include("3.part"),
+    );
+    state += test_dir.to_string() + "/a";
+    state += test_dir.to_string() + "/b";
+
+    let (moves, cost) = state.relocate().unwrap();
+    println!("{cost}: {moves:?}");
+    // The excluded file stays put, so its directory cannot be renamed whole
+    let full_test_dir = PathBuf::from(test_dir).canonicalize()?;
+    assert_eq!(
+        vec![
+            Move {
+                source: full_test_dir.join("a/c/1.txt"),
+                target: full_test_dir.join("b/c/1.txt"),
+                ..Default::default()
+            },
+            Move {
+                source: full_test_dir.join("a/c/3.part"),
+                target: full_test_dir.join("b/c/3.part"),
+                ..Default::default()
+            },
+        ],
+        moves
+    );
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn hard_links() -> io::Result<()> {
+    let test_dir = "test_dir_hard_links";
+
+    setup(
+        test_dir,
+        &[
+            ("a/c/1.txt", "1"),
+            ("b/c/2.txt", "2"),
+            ("b/c/3.txt", "3"),
+            ("b/c/4.txt", "4"),
+        ],
+    )?;
+    fs::create_dir_all(PathBuf::from(test_dir).join("a/c/d"))?;
+    fs::hard_link(
+        PathBuf::from(test_dir).join("a/c/1.txt"),
+        PathBuf::from(test_dir).join("a/c/d/1.link"),
+    )?;
+
+    let mut state = State::default();
+    state += test_dir.to_string() + "/a";
+    state += test_dir.to_string() + "/b";
+
+    // Both links are a single entry, moved together, then their emptied directory
+    let plan = state.plan().unwrap();
+    println!("{plan:?}");
+    assert_eq!(3, plan.moves.len());
+    let report = Executor::default().execute(&plan.moves)?;
+    assert!(report.failures.is_empty(), "{report:?}");
+
+    let full_test_dir = PathBuf::from(test_dir).canonicalize()?;
+    let file = fs::metadata(full_test_dir.join("b/c/1.txt"))?;
+    let link = fs::metadata(full_test_dir.join("b/c/d/1.link"))?;
+    assert_eq!(2, file.nlink());
+    assert_eq!(file.ino(), link.ino());
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn hard_links_left_in_place() -> io::Result<()> {
+    let test_dir = "test_dir_hard_links_left_in_place";
+
+    setup(
+        test_dir,
+        &[
+            ("a/c/1.txt", "1"),
+            ("a/c/2.txt", "2"),
+            ("a/c/3.txt", "3"),
+            ("b/c/4.txt", "4444"),
+            ("b/c/5.txt", "5555"),
+            ("outside.txt", ""),
+        ],
+    )?;
+    let test_dir_path = PathBuf::from(test_dir);
+    // One link beyond the roots, and one in another subdir
+    fs::remove_file(test_dir_path.join("outside.txt"))?;
+    fs::hard_link(
+        test_dir_path.join("a/c/1.txt"),
+        test_dir_path.join("outside.txt"),
+    )?;
+    fs::create_dir_all(test_dir_path.join("a/e"))?;
+    fs::hard_link(
+        test_dir_path.join("a/c/2.txt"),
+        test_dir_path.join("a/e/2.link"),
+    )?;
+
+    let mut state = State::default();
+    let skipped = state.try_add_root(&(test_dir.to_string() + "/a")).unwrap();
+    state += test_dir.to_string() + "/b";
+    assert_eq!(3, skipped.len(), "{skipped:?}");
+    let reason = |path: &str| {
+        skipped
+            .iter()
+            .find(|skipped| skipped.path.ends_with(path))
+            .map(|skipped| skipped.reason.to_string())
+    };
+    assert_eq!(
+        Some("only 1 of its 2 hard links are scanned".to_string()),
+        reason("a/c/1.txt")
+    );
+    for path in ["a/c/2.txt", "a/e/2.link"] {
+        assert_eq!(
+            Some("hard linked from another subdir, \"e\"".to_string()),
+            reason(path)
+        );
+    }
+
+    // Only the file free to move does, and the directories kept by the others stay
+    let plan = state.plan().unwrap();
+    println!("{plan:?}");
+    assert_eq!(1, plan.cost);
+    let report = Executor::default().execute(&plan.moves)?;
+    assert!(report.failures.is_empty(), "{report:?}");
+    assert!(test_dir_path.join("b/c/3.txt").exists());
+    assert_eq!(2, fs::metadata(test_dir_path.join("a/c/1.txt"))?.nlink());
+    assert_eq!(2, fs::metadata(test_dir_path.join("a/e/2.link"))?.nlink());
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+#[ignore = "needs /dev/shm on another device than the working directory"]
+fn cross_filesystem() -> io::Result<()> {
+    let test_dir = "test_dir_cross_filesystem";
+    // A second filesystem, in memory
+    let other_dir = "/dev/shm/test_dir_cross_filesystem";
+    assert_ne!(fs::metadata("/dev/shm")?.dev(), fs::metadata(".")?.dev());
+
+    setup(test_dir, &[("a/c/1.txt", "hello")])?;
+    setup(
+        other_dir,
+        &[
+            ("b/c/2.txt", "hello_world"),
+            ("b/c/3.txt", "1234567890"),
+            ("b/c/4.txt", "3"),
+        ],
+    )?;
+    fs::create_dir_all(PathBuf::from(test_dir).join("a/c/d"))?;
+    fs::hard_link(
+        PathBuf::from(test_dir).join("a/c/1.txt"),
+        PathBuf::from(test_dir).join("a/c/d/1.link"),
+    )?;
+    fs::set_permissions(
+        PathBuf::from(test_dir).join("a/c/1.txt"),
+        fs::Permissions::from_mode(0o640),
+    )?;
+
+    let mut state = State::default();
+    state += test_dir.to_string() + "/a";
+    state += other_dir.to_string() + "/b";
+
+    // The file is copied, then linked, rather than its directories renamed
+    let plan = state.plan().unwrap();
+    println!("{plan:?}");
+    assert_ne!(plan.roots[0].filesystem, plan.roots[1].filesystem);
+    assert_eq!(5, plan.cost);
+    assert!(plan.moves.iter().any(|m| m.hard_link.is_some()));
+    let report = Executor::default()
+        .verify(HashAlgorithm::Sha256)
+        .execute(&plan.moves)?;
+    assert!(report.failures.is_empty(), "{report:?}");
+
+    let other_dir_path = PathBuf::from(other_dir);
+    assert_eq!(
+        "hello",
+        fs::read_to_string(other_dir_path.join("b/c/1.txt"))?
+    );
+    let file = fs::metadata(other_dir_path.join("b/c/1.txt"))?;
+    let link = fs::metadata(other_dir_path.join("b/c/d/1.link"))?;
+    assert_eq!(2, file.nlink());
+    assert_eq!(file.ino(), link.ino());
+    assert_eq!(0o640, file.mode() & 0o7777);
+    assert!(!PathBuf::from(test_dir).join("a/c").exists());
+    assert!(PathBuf::from(test_dir).join("a").exists());
+
+    cleanup(test_dir)?;
+    cleanup(other_dir)?;
+    Ok(())
+}
+
+#[test]
+fn symlinks_move_with_their_group() -> io::Result<()> {
+    let test_dir = "test_dir_symlinks_move_with_their_group";
+
+    setup(
+        test_dir,
+        &[
+            ("a/c/d/1.txt", "1"),
+            ("b/c/2.txt", "2"),
+            ("b/c/3.txt", "3"),
+            ("b/c/4.txt", "4"),
+            ("b/c/5.txt", "5"),
+        ],
+    )?;
+    let full_test_dir = PathBuf::from(test_dir).canonicalize()?;
+    std::os::unix::fs::symlink(
+        full_test_dir.join("a/c/d/1.txt"),
+        full_test_dir.join("a/c/d/absolute"),
+    )?;
+    std::os::unix::fs::symlink("1.txt", full_test_dir.join("a/c/d/relative"))?;
+
+    let mut state = State::default();
+    state.set_symlink_policy(SymlinkPolicy::Rewrite);
+    state += test_dir.to_string() + "/a";
+    state += test_dir.to_string() + "/b";
+
+    let plan = state.plan().unwrap();
+    println!("{plan:?}");
+    // The absolute link is made afresh, so its directory is not renamed whole
+    assert!(plan.moves.contains(&Move {
+        source: full_test_dir.join("a/c/d/absolute"),
+        target: full_test_dir.join("b/c/d/absolute"),
+        symlink: Some(full_test_dir.join("b/c/d/1.txt")),
+        ..Default::default()
+    }));
+    let report = Executor::default().execute(&plan.moves)?;
+    assert!(report.failures.is_empty(), "{report:?}");
+
+    assert_eq!(
+        full_test_dir.join("b/c/d/1.txt"),
+        fs::read_link(full_test_dir.join("b/c/d/absolute"))?
+    );
+    assert_eq!(
+        PathBuf::from("1.txt"),
+        fs::read_link(full_test_dir.join("b/c/d/relative"))?
+    );
+    assert_eq!(
+        "1",
+        fs::read_to_string(full_test_dir.join("b/c/d/absolute"))?
+    );
+    assert!(fs::symlink_metadata(full_test_dir.join("a/c/d/absolute")).is_err());
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn empty_directories() -> io::Result<()> {
+    let test_dir = "test_dir_empty_directories";
+
+    for keep in [false, true] {
+        setup(
+            test_dir,
+            &[
+                ("a/c/1.txt", "1"),
+                ("b/c/2.txt", "2"),
+                ("b/c/3.txt", "3"),
+                ("b/c/4.txt", "4"),
+            ],
+        )?;
+        let test_dir_path = PathBuf::from(test_dir);
+        fs::create_dir(test_dir_path.join("a/c/empty"))?;
+        fs::set_permissions(
+            test_dir_path.join("a/c/empty"),
+            fs::Permissions::from_mode(0o700),
+        )?;
+
+        let mut state = State::default();
+        state.set_keep_source_dirs(keep);
+        state += test_dir.to_string() + "/a";
+        state += test_dir.to_string() + "/b";
+
+        let plan = state.plan().unwrap();
+        println!("{plan:?}");
+        let report = Executor::default().execute(&plan.moves)?;
+        assert!(report.failures.is_empty(), "{report:?}");
+
+        // The empty directory goes with its group, whether or not the emptied source is kept
+        let moved = fs::metadata(test_dir_path.join("b/c/empty"))?;
+        assert!(moved.is_dir());
+        assert_eq!(0o700, moved.permissions().mode() & 0o7777);
+        assert!(!test_dir_path.join("a/c/empty").exists());
+        assert_eq!(keep, test_dir_path.join("a/c").exists());
+        assert!(test_dir_path.join("a").exists());
+
+        cleanup(test_dir)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn drain_root() -> io::Result<()> {
+    let test_dir = "test_dir_drain_root";
+
+    setup(
+        test_dir,
+        &[
+            ("a/c/1.txt", "1"),
+            ("b/e/2.txt", "2"),
+            ("d/c/3.txt", "3"),
+            ("d/c/4.txt", "4"),
+            ("d/e/5.txt", "5"),
+            ("d/f/6.txt", "6"),
+        ],
+    )?;
+
+    let mut state = State::default();
+    state += test_dir.to_string() + "/a";
+    state += test_dir.to_string() + "/b";
+    state
+        .try_add_drained(&(test_dir.to_string() + "/d"))
+        .unwrap();
+
+    let plan = state.plan().unwrap();
+    println!("{plan:?}");
+    let report = Executor::default().execute(&plan.moves)?;
+    assert!(report.failures.is_empty(), "{report:?}");
+
+    // Each subdir joins the rest of it, though c has more files on d
+    let test_dir_path = PathBuf::from(test_dir);
+    assert!(test_dir_path.join("a/c/3.txt").exists());
+    assert!(test_dir_path.join("a/c/4.txt").exists());
+    assert!(test_dir_path.join("b/e/5.txt").exists());
+    assert!(test_dir_path.join("a/f/6.txt").exists() || test_dir_path.join("b/f/6.txt").exists());
+    assert_eq!(0, fs::read_dir(test_dir_path.join("d"))?.count());
+
+    cleanup(test_dir)?;
+    Ok(())
+}
+
+#[test]
+fn placement_constraints() -> io::Result<()> {
+    let test_dir = "test_dir_placement_constraints";
+
+    setup(
+        test_dir,
+        &[
+            ("a/c/1.txt", "1"),
+            ("b/c/2.txt", "2"),
+            ("b/c/3.txt", "3"),
+            ("a/e/4.txt", "4"),
+            ("a/e/5.txt", "5"),
+            ("b/e/6.txt", "6"),
+        ],
+    )?;
+
+    let mut state = State::default();
+    state += test_dir.to_string() + "/a";
+    state += test_dir.to_string() + "/b";
+    // Each subdir is pulled away from the root holding most of it
+    state.set_constraints(
+        Constraints::default()
+            .pin("./c", test_dir.to_string() + "/a")
+            .forbid("e", test_dir.to_string() + "/a"),
+    )?;
+
+    let plan = state.plan().unwrap();
+    println!("{plan:?}");
+    let report = Executor::default().execute(&plan.moves)?;
+    assert!(report.failures.is_empty(), "{report:?}");
+
+    let test_dir_path = PathBuf::from(test_dir);
+    assert!(test_dir_path.join("a/c/2.txt").exists());
+    assert!(test_dir_path.join("a/c/3.txt").exists());
+    assert!(test_dir_path.join("b/e/4.txt").exists());
+    assert!(test_dir_path.join("b/e/5.txt").exists());
+    assert!(!test_dir_path.join("b/c").exists());
+    assert!(!test_dir_path.join("a/e").exists());
+
+    // Naming a root or subdir which was never scanned is refused
+    assert!(state
+        .set_constraints(Constraints::default().no_target(test_dir.to_string() + "/z"))
+        .is_err());
+    assert!(state
+        .set_constraints(Constraints::default().pin("x", test_dir.to_string() + "/a"))
+        .is_err());
+
+    cleanup(test_dir)?;
+    Ok(())
+}